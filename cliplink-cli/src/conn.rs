@@ -1,45 +1,110 @@
-use std::{
-    io::{Read, Write},
-    marker::PhantomData,
-    net::TcpStream,
+use std::{marker::PhantomData, net::TcpStream};
+
+use cliplink_common::{CodecError, Decoder, Encoder, Frame, FrameError, read_frame, write_frame};
+use cliplink_crypto::{
+    ClientKex, Direction, KexMode, PubKey, RsaPadding, SessionKeys, Transcript, sequence_nonce,
 };
 
-use cliplink_common::{PACKET_SIZE, Packet, PacketError};
-use cliplink_crypto::{AES_256_SIZE, Aes256, GCM_AUTHENTICATION_TAG_SIZE, NONCE_SIZE, RsaPrivKey};
+use crate::{
+    identity::Identity,
+    known_hosts::{KnownHosts, KnownHostsError},
+};
 
 pub enum Input {
-    SshHandshakeAck(Vec<u8>),
-    SshHandshakeDeny,
+    SshHandshakeAck {
+        reply: Vec<u8>,
+        challenge: Vec<u8>,
+        host_key: Vec<u8>,
+        host_signature: Vec<u8>,
+    },
+    SshHandshakeDeny(String),
+    AuthAck,
 }
 
 pub enum Output<'a> {
-    SshHandshake(&'a [u8]),
+    SshHandshake {
+        mode: KexMode,
+        padding: RsaPadding,
+        pub_key: &'a [u8],
+        eph_pub_key: &'a [u8],
+    },
+    Auth(&'a [u8]),
 }
 
-impl TryFrom<&Packet> for Input {
-    type Error = PacketError;
-
-    fn try_from(packet: &Packet) -> Result<Self, Self::Error> {
-        match packet.ty()? {
-            b"sshsynack" => Ok(Self::SshHandshakeAck(packet.payload()?.to_vec())),
-            b"sshsyndeny" => Ok(Self::SshHandshakeDeny),
-            _ => unimplemented!("unexpected type"),
+impl TryFrom<&Frame> for Input {
+    type Error = ConnectionError;
+
+    fn try_from(frame: &Frame) -> Result<Self, Self::Error> {
+        match frame.ty.as_slice() {
+            b"sshsynack" => {
+                let mut decoder = Decoder::new(&frame.payload);
+                let input = Self::SshHandshakeAck {
+                    reply: decoder.get_bytes()?.to_vec(),
+                    challenge: decoder.get_bytes()?.to_vec(),
+                    host_key: decoder.get_bytes()?.to_vec(),
+                    host_signature: decoder.get_bytes()?.to_vec(),
+                };
+                decoder.finish()?;
+
+                Ok(input)
+            }
+            b"sshsyndeny" => Ok(Self::SshHandshakeDeny(
+                String::from_utf8_lossy(&frame.payload).to_string(),
+            )),
+            b"sshauthack" => Ok(Self::AuthAck),
+            _ => Err(unexpected_type(frame)),
         }
     }
 }
 
-impl<'a> From<&Output<'a>> for Packet {
+impl<'a> From<&Output<'a>> for Frame {
     fn from(pl: &Output<'a>) -> Self {
         match pl {
-            Output::SshHandshake(pl) => Packet::new(b"sshsyn", pl),
+            Output::SshHandshake {
+                mode,
+                padding,
+                pub_key,
+                eph_pub_key,
+            } => Frame::new(
+                b"sshsyn",
+                &Encoder::default()
+                    .put_u8(*mode as u8)
+                    .put_u8(*padding as u8)
+                    .put_bytes(pub_key)
+                    .put_bytes(eph_pub_key)
+                    .finish(),
+            ),
+            Output::Auth(signature) => Frame::new(b"sshauth", signature),
         }
     }
 }
 
+fn unexpected_type(frame: &Frame) -> ConnectionError {
+    ConnectionError::UnexpectedType(String::from_utf8_lossy(&frame.ty).to_string())
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum ConnectionError {
-    #[error("unsupported key type")]
-    UnsupportedKeyType,
+    #[error("handshake denied: {0}")]
+    HandshakeDenied(String),
+
+    #[error("unexpected type {0:?}")]
+    UnexpectedType(String),
+
+    #[error("malformed secure frame")]
+    MalformedSecureFrame,
+
+    #[error("out of sequence secure frame: expected {expected}, got {actual}")]
+    OutOfSequence { expected: u64, actual: u64 },
+
+    #[error("secure frame {0} failed to decrypt, it was replayed or altered")]
+    ReplayedOrAltered(u64),
+
+    #[error("host key verification failed")]
+    HostKeyVerification,
+
+    #[error(transparent)]
+    KnownHosts(#[from] KnownHostsError),
 
     #[error(transparent)]
     Aes(#[from] cliplink_crypto::AesError),
@@ -48,19 +113,33 @@ pub enum ConnectionError {
     IOError(#[from] std::io::Error),
 
     #[error(transparent)]
-    PacketError(#[from] PacketError),
+    CodecError(#[from] CodecError),
+
+    #[error(transparent)]
+    FrameError(#[from] FrameError),
 
     #[error(transparent)]
     RsaError(#[from] cliplink_crypto::RsaError),
+
+    #[cfg(unix)]
+    #[error(transparent)]
+    AgentError(#[from] cliplink_crypto::AgentError),
 }
 
+const SECURE_FRAME_TYPE: &[u8] = b"sec";
+
 pub struct Handshake;
 pub struct HandshakeAck;
+pub struct Auth;
 pub struct Secure;
 
 pub struct Connection<State> {
-    aes_key: Option<Aes256>,
-    rsa_priv_key: Option<RsaPrivKey>,
+    session_keys: Option<SessionKeys>,
+    kex: Option<ClientKex>,
+    transcript: Transcript,
+    send_seq: u64,
+    recv_seq: u64,
+    identity: Option<Identity>,
     phantom: PhantomData<State>,
     stream: TcpStream,
 }
@@ -68,126 +147,353 @@ pub struct Connection<State> {
 impl<T> Connection<T> {
     fn mutate<N>(self) -> Connection<N> {
         Connection {
-            aes_key: self.aes_key,
-            rsa_priv_key: self.rsa_priv_key,
+            session_keys: self.session_keys,
+            kex: self.kex,
+            transcript: self.transcript,
+            send_seq: self.send_seq,
+            recv_seq: self.recv_seq,
+            identity: self.identity,
             phantom: PhantomData::<N>,
             stream: self.stream,
         }
     }
 
-    pub fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), std::io::Error> {
-        let buf_len = match self.stream.read(buf) {
-            Ok(buf_len) => buf_len,
-            Err(err) => {
-                eprintln!("read failure, closing socket: {err:?}");
-                self.stream
-                    .shutdown(std::net::Shutdown::Both)
-                    .expect("failed to shutdown");
-
-                panic!("{err}");
-            }
-        };
-
-        buf[buf_len..].fill(0x0);
-
-        dbg!("read", buf.len(), buf_len);
-        Ok(())
+    pub fn read_frame(&mut self) -> Result<Frame, ConnectionError> {
+        Ok(read_frame(&mut self.stream)?)
     }
 
-    fn write_bytes(&mut self, buf: &[u8]) -> Result<usize, ConnectionError> {
-        let buf_len = self.stream.write(&buf)?;
-
-        dbg!("write", buf.len(), buf_len);
-        Ok(buf_len)
+    fn write_frame(&mut self, frame: &Frame) -> Result<(), ConnectionError> {
+        Ok(write_frame(&mut self.stream, frame)?)
     }
 
-    fn write_output(&mut self, output: Output) -> Result<usize, ConnectionError> {
-        self.write_bytes(Packet::from(&output).as_bytes())
+    fn write_output(&mut self, output: Output) -> Result<(), ConnectionError> {
+        self.write_frame(&Frame::from(&output))
     }
 }
 
 // sshsyn > sshsynack | sshsyndeny
 //
-// client                  | server
-// pubkeysyn (pub ssh key) > pubkeyack
-// enckeyack               < enckey (encrypted)
-// copy   (payload)        > copyack
-// paste                   < pasteack (payload)
+// client                                  | server
+// sshsyn (kex mode, rsa padding,          > sshsynack (eph key, sealed secret, challenge,
+//         pub ssh key, eph key)           |            host key, host transcript signature)
+// sshauth (transcript signature)          > sshauthack | sshsyndeny
 impl Connection<Handshake> {
     pub fn from(stream: TcpStream) -> Self {
         Self {
-            aes_key: None,
-            rsa_priv_key: None,
+            session_keys: None,
+            kex: None,
+            transcript: Transcript::default(),
+            send_seq: 0,
+            recv_seq: 0,
+            identity: None,
             phantom: PhantomData::<Handshake>,
             stream,
         }
     }
 
-    pub fn send_ssh_key(mut self) -> Result<Connection<HandshakeAck>, ConnectionError> {
-        let rsa_priv_key = RsaPrivKey::default();
-        let rsa_pub_key_openssh = rsa_priv_key.pub_key().to_openssh(None)?;
-
-        self.write_output(Output::SshHandshake(rsa_pub_key_openssh.as_bytes()))?;
-
-        self.rsa_priv_key = Some(rsa_priv_key);
+    pub fn send_ssh_key(
+        mut self,
+        identity: Identity,
+        padding: RsaPadding,
+    ) -> Result<Connection<HandshakeAck>, ConnectionError> {
+        let pub_key_openssh = identity.pub_key_openssh()?;
+        let kex = ClientKex::new(identity.kex_mode(), padding);
+
+        let frame = Frame::from(&Output::SshHandshake {
+            mode: kex.mode(),
+            padding: kex.padding(),
+            pub_key: pub_key_openssh.as_bytes(),
+            eph_pub_key: kex.eph_pub_key(),
+        });
+        self.transcript.update(&frame.payload);
+        self.write_frame(&frame)?;
+
+        self.identity = Some(identity);
+        self.kex = Some(kex);
 
         Ok(self.mutate::<HandshakeAck>())
     }
 }
 
 impl Connection<HandshakeAck> {
-    pub fn parse_aes256_key(
+    /// Authenticates the server against `known_hosts`, derives the session keys and answers the
+    /// server challenge by signing the transcript.
+    pub fn parse_session_keys(
         mut self,
-        packet: &Packet,
-    ) -> Result<Connection<Secure>, ConnectionError> {
-        let Input::SshHandshakeAck(aes_key) = Input::try_from(packet)? else {
-            return Err(ConnectionError::UnsupportedKeyType);
+        frame: &Frame,
+        known_hosts: &KnownHosts,
+        host: &str,
+    ) -> Result<Connection<Auth>, ConnectionError> {
+        let (reply, challenge, host_key, host_signature) = match Input::try_from(frame)? {
+            Input::SshHandshakeAck {
+                reply,
+                challenge,
+                host_key,
+                host_signature,
+            } => (reply, challenge, host_key, host_signature),
+            Input::SshHandshakeDeny(reason) => {
+                return Err(ConnectionError::HandshakeDenied(reason));
+            }
+            _ => return Err(unexpected_type(frame)),
         };
 
-        let rsa_priv_key = self.rsa_priv_key.as_ref().expect("no rsa key available");
+        let mut transcript = self.transcript.clone();
+        transcript.update(&reply);
+        transcript.update(&challenge);
+        transcript.update(&host_key);
 
-        let aes_key_dec_buf = rsa_priv_key.decrypt_pkcs1v15(&aes_key)?;
+        let host_key = PubKey::from_openssh(&host_key)?;
+        host_key
+            .verify(&transcript.host_message(), &host_signature)
+            .map_err(|_| ConnectionError::HostKeyVerification)?;
+        known_hosts.verify(host, &host_key)?;
 
-        if aes_key_dec_buf.len() > AES_256_SIZE {
-            panic!("aes256 incompatible");
-        }
+        let identity = self.identity.as_mut().expect("no ssh key available");
+        let kex = self.kex.take().expect("no key exchange available");
 
-        let mut buf = [0u8; AES_256_SIZE];
-        buf.copy_from_slice(&aes_key_dec_buf);
+        self.session_keys = Some(identity.finish_kex(kex, &reply)?);
 
-        self.aes_key = Aes256::try_from(buf)?.into();
+        self.transcript.update(&frame.payload);
+        let signature = identity.sign(&self.transcript.auth_message())?;
+        self.write_output(Output::Auth(&signature))?;
 
-        Ok(self.mutate::<Secure>())
+        Ok(self.mutate::<Auth>())
+    }
+}
+
+impl Connection<Auth> {
+    pub fn parse_auth_ack(self, frame: &Frame) -> Result<Connection<Secure>, ConnectionError> {
+        match Input::try_from(frame)? {
+            Input::AuthAck => Ok(self.mutate::<Secure>()),
+            Input::SshHandshakeDeny(reason) => Err(ConnectionError::HandshakeDenied(reason)),
+            _ => Err(unexpected_type(frame)),
+        }
     }
 }
 
 impl Connection<Secure> {
-    pub fn read_packet_sec(&mut self) -> Result<Packet, ConnectionError> {
-        let mut buf = [0u8; NONCE_SIZE + PACKET_SIZE + GCM_AUTHENTICATION_TAG_SIZE];
-        let _ = self.read_bytes(&mut buf)?;
+    pub fn identity_mut(&mut self) -> &mut Identity {
+        self.identity.as_mut().expect("no ssh key available")
+    }
+
+    /// Reads one encrypted frame from the stream and decodes the `Frame` sealed within it.
+    ///
+    /// Secure frames carry their sequence number as `request_id` and the ciphertext of the wire
+    /// encoding of the inner frame as payload. Each direction counts from zero and the nonce is
+    /// derived from the expected sequence number, so replayed, dropped or reordered frames are
+    /// rejected.
+    pub fn read_packet_sec(&mut self) -> Result<Frame, ConnectionError> {
+        let frame = self.read_frame()?;
+
+        if frame.ty != SECURE_FRAME_TYPE {
+            return Err(ConnectionError::MalformedSecureFrame);
+        }
+
+        if frame.request_id != self.recv_seq {
+            return Err(ConnectionError::OutOfSequence {
+                expected: self.recv_seq,
+                actual: frame.request_id,
+            });
+        }
+
+        let session_keys = self
+            .session_keys
+            .as_ref()
+            .expect("no session keys available");
+        // A frame replayed under the expected sequence number was sealed under another nonce.
+        let dec_buf = session_keys
+            .server
+            .decrypt(
+                sequence_nonce(Direction::ServerToClient, self.recv_seq),
+                &frame.payload,
+            )
+            .map_err(|_| ConnectionError::ReplayedOrAltered(self.recv_seq))?;
+        self.recv_seq += 1;
+
+        Ok(read_frame(&mut dec_buf.as_slice())?)
+    }
 
-        let mut nonce = [0u8; NONCE_SIZE];
-        nonce.copy_from_slice(&buf[0..NONCE_SIZE]);
+    pub fn write_packet_sec(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let session_keys = self
+            .session_keys
+            .as_ref()
+            .expect("no session keys available");
 
-        let aes_key = self.aes_key.as_ref().expect("no aes key available");
-        let mut dec_buf = [0u8; PACKET_SIZE];
-        dec_buf.copy_from_slice(&aes_key.decrypt(nonce, &buf[NONCE_SIZE..])?);
+        let mut plain_buf = Vec::new();
+        write_frame(&mut plain_buf, &frame)?;
 
-        Ok(Packet::from_bytes(&dec_buf))
+        let enc_buf = session_keys.client.encrypt_with_nonce(
+            sequence_nonce(Direction::ClientToServer, self.send_seq),
+            &plain_buf,
+        )?;
+
+        let mut frame = Frame::new(SECURE_FRAME_TYPE, &enc_buf);
+        frame.request_id = self.send_seq;
+        self.send_seq += 1;
+
+        self.write_frame(&frame)
     }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::{net::TcpListener, path::Path, thread::JoinHandle};
 
-    pub fn write_packet_sec(&mut self, packet: Packet) -> Result<usize, ConnectionError> {
-        let aes_key = self.aes_key.as_ref().expect("no aes key available");
+    use cliplink_crypto::{
+        AES_256_SIZE, Aes256, Agent, Ed25519PrivKey, PrivKey, ServerKex, challenge,
+        test_util::stand_in,
+    };
 
-        let (nonce, enc_buf) = aes_key.encrypt(packet.as_bytes())?;
-        dbg!(nonce.len(), enc_buf.len());
+    use super::*;
 
-        let mut inline_buf = Vec::with_capacity(nonce.len() + enc_buf.len());
-        inline_buf.extend_from_slice(&nonce);
-        inline_buf.extend_from_slice(&enc_buf);
+    /// `ssh-agent` stand-in holding `key` alone, for one connection.
+    fn agent(key: PrivKey, dir: &Path) -> Identity {
+        let (path, _) = stand_in(vec![key], false, dir);
+        let mut agent = Agent::connect_to(&path).unwrap();
+        let key = agent.identities().unwrap().pop().unwrap();
 
-        let buf_len = self.write_bytes(&inline_buf)?;
+        Identity::Agent { agent, key }
+    }
+
+    /// Server side of the handshake, as cliplink-server runs it, then a `ping` > `pong` exchange
+    /// over secure frames.
+    fn server(listener: TcpListener, host_key: PrivKey) -> JoinHandle<()> {
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let syn = read_frame(&mut stream).unwrap();
+            let mut decoder = Decoder::new(&syn.payload);
+            let mode = KexMode::try_from(decoder.get_u8().unwrap()).unwrap();
+            let padding = RsaPadding::try_from(decoder.get_u8().unwrap()).unwrap();
+            let pub_key = PubKey::from_openssh(decoder.get_bytes().unwrap()).unwrap();
+            let eph_pub_key = decoder.get_bytes().unwrap();
+            assert_eq!(mode, KexMode::Signed);
+
+            let (reply, session_keys) =
+                ServerKex::respond(mode, padding, &pub_key, eph_pub_key).unwrap();
+            let challenge = challenge();
+            let host_key_openssh = host_key.pub_key().to_openssh(None).unwrap();
+
+            let mut transcript = Transcript::default();
+            transcript.update(&syn.payload);
+            let mut host_transcript = transcript.clone();
+            host_transcript.update(&reply);
+            host_transcript.update(&challenge);
+            host_transcript.update(host_key_openssh.as_bytes());
+
+            let syn_ack = Frame::new(
+                b"sshsynack",
+                &Encoder::default()
+                    .put_bytes(&reply)
+                    .put_bytes(&challenge)
+                    .put_str(&host_key_openssh)
+                    .put_bytes(&host_key.sign(&host_transcript.host_message()))
+                    .finish(),
+            );
+            write_frame(&mut stream, &syn_ack).unwrap();
+            transcript.update(&syn_ack.payload);
+
+            let auth = read_frame(&mut stream).unwrap();
+            assert_eq!(auth.ty, b"sshauth");
+            pub_key
+                .verify(&transcript.auth_message(), &auth.payload)
+                .unwrap();
+            write_frame(&mut stream, &Frame::new(b"sshauthack", &[])).unwrap();
+
+            let sec = read_frame(&mut stream).unwrap();
+            let plain_buf = session_keys
+                .client
+                .decrypt(
+                    sequence_nonce(Direction::ClientToServer, sec.request_id),
+                    &sec.payload,
+                )
+                .unwrap();
+            assert_eq!(read_frame(&mut plain_buf.as_slice()).unwrap().ty, b"ping");
+
+            let mut plain_buf = Vec::new();
+            write_frame(&mut plain_buf, &Frame::new(b"pong", &[])).unwrap();
+            let enc_buf = session_keys
+                .server
+                .encrypt_with_nonce(sequence_nonce(Direction::ServerToClient, 0), &plain_buf)
+                .unwrap();
+            write_frame(&mut stream, &Frame::new(SECURE_FRAME_TYPE, &enc_buf)).unwrap();
+        })
+    }
+
+    #[test]
+    fn signed_handshake_with_agent() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = agent(PrivKey::Ed25519(Ed25519PrivKey::generate()), dir.path());
+        assert_eq!(identity.kex_mode(), KexMode::Signed);
+
+        let host_key = PrivKey::Ed25519(Ed25519PrivKey::generate());
+        let known_hosts = KnownHosts::Pinned(host_key.pub_key().fingerprint().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = server(listener, host_key);
+
+        let conn = Connection::from(TcpStream::connect(addr).unwrap());
+        let mut conn = conn.send_ssh_key(identity, RsaPadding::Oaep).unwrap();
+        let frame = conn.read_frame().unwrap();
+        let mut conn = conn
+            .parse_session_keys(&frame, &known_hosts, &addr.to_string())
+            .unwrap();
+        let frame = conn.read_frame().unwrap();
+        let mut conn = conn.parse_auth_ack(&frame).unwrap();
+
+        conn.write_packet_sec(Frame::new(b"ping", &[])).unwrap();
+        assert_eq!(conn.read_packet_sec().unwrap().ty, b"pong");
+
+        handle.join().unwrap();
+    }
+
+    /// Seals `frame` as the server does for sequence number `seq`.
+    fn sealed(key: &Aes256, seq: u64, frame: &Frame) -> Frame {
+        let mut plain_buf = Vec::new();
+        write_frame(&mut plain_buf, frame).unwrap();
+        let enc_buf = key
+            .encrypt_with_nonce(sequence_nonce(Direction::ServerToClient, seq), &plain_buf)
+            .unwrap();
+
+        let mut frame = Frame::new(SECURE_FRAME_TYPE, &enc_buf);
+        frame.request_id = seq;
+        frame
+    }
+
+    #[test]
+    fn rejects_replayed_frames() {
+        let key = [7u8; AES_256_SIZE];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+
+        let mut conn = Connection::from(stream).mutate::<Secure>();
+        conn.session_keys = Some(SessionKeys {
+            client: Aes256::try_from(key).unwrap(),
+            server: Aes256::try_from(key).unwrap(),
+        });
+
+        let server = Aes256::try_from(key).unwrap();
+        let first = sealed(&server, 0, &Frame::new(b"first", &[]));
+        let mut rewritten = first.clone();
+        rewritten.request_id = 1;
+        let second = sealed(&server, 1, &Frame::new(b"second", &[]));
+        for frame in [&first, &first, &rewritten, &second] {
+            write_frame(&mut peer, frame).unwrap();
+        }
 
-        Ok(buf_len)
+        assert_eq!(conn.read_packet_sec().unwrap().ty, b"first");
+        assert!(matches!(
+            conn.read_packet_sec(),
+            Err(ConnectionError::OutOfSequence {
+                expected: 1,
+                actual: 0
+            })
+        ));
+        assert!(matches!(
+            conn.read_packet_sec(),
+            Err(ConnectionError::ReplayedOrAltered(1))
+        ));
+        assert_eq!(conn.read_packet_sec().unwrap().ty, b"second");
     }
 }
@@ -0,0 +1,161 @@
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use cliplink_crypto::{Agent, AgentKey};
+use cliplink_crypto::{
+    CLIP_KEY_MESSAGE, ClientKex, ClipKey, KexMode, PrivKey, RsaError, SessionKeys,
+};
+use zeroize::Zeroizing;
+
+use crate::{conn::ConnectionError, session::SessionError};
+
+/// Passphrase for an encrypted identity, instead of prompting for it.
+pub const PASSPHRASE_ENV: &str = "CL_PASSPHRASE";
+
+/// File whose first line is the passphrase for an encrypted identity.
+pub const PASSPHRASE_FILE_ENV: &str = "CL_PASSPHRASE_FILE";
+
+/// Key the client authenticates with: a private key loaded from disk, or one kept in `ssh-agent`.
+pub enum Identity {
+    Key(PrivKey),
+    #[cfg(unix)]
+    Agent {
+        agent: Agent,
+        key: AgentKey,
+    },
+}
+
+impl Identity {
+    /// Key exchange mode to negotiate. Agent keys can sign but not decrypt, so nothing can be
+    /// sealed to them.
+    pub fn kex_mode(&self) -> KexMode {
+        match self {
+            Self::Key(_) => KexMode::Ephemeral,
+            #[cfg(unix)]
+            Self::Agent { .. } => KexMode::Signed,
+        }
+    }
+
+    pub fn pub_key_openssh(&self) -> Result<String, RsaError> {
+        match self {
+            Self::Key(priv_key) => priv_key.pub_key().to_openssh(None),
+            #[cfg(unix)]
+            Self::Agent { key, .. } => key.pub_key().to_openssh(None),
+        }
+    }
+
+    pub fn finish_kex(&self, kex: ClientKex, reply: &[u8]) -> Result<SessionKeys, RsaError> {
+        match self {
+            Self::Key(priv_key) => kex.finish(priv_key, reply),
+            #[cfg(unix)]
+            Self::Agent { key, .. } => kex.finish_signed(key.pub_key(), reply),
+        }
+    }
+
+    pub fn sign(&mut self, buf: &[u8]) -> Result<Vec<u8>, ConnectionError> {
+        match self {
+            Self::Key(priv_key) => Ok(priv_key.sign(buf)),
+            #[cfg(unix)]
+            Self::Agent { agent, key } => Ok(agent.sign(key, buf)?),
+        }
+    }
+
+    /// Derives the key for end-to-end encrypted clips, the same on every device holding this
+    /// identity.
+    pub fn clip_key(&mut self) -> Result<ClipKey, SessionError> {
+        Ok(ClipKey::derive(&self.sign(CLIP_KEY_MESSAGE)?)?)
+    }
+}
+
+/// Loads the private key at `path`, or `~/.ssh/id_ed25519` / `~/.ssh/id_rsa` when `None`.
+///
+/// An encrypted key is decrypted with the passphrase from `CL_PASSPHRASE`, then
+/// `CL_PASSPHRASE_FILE`, then `passphrase_file`, prompting on the terminal when none is set.
+pub fn from_file(
+    path: Option<PathBuf>,
+    passphrase_file: Option<PathBuf>,
+) -> Result<Identity, SessionError> {
+    let path = match path {
+        Some(path) => path,
+        None => PrivKey::default_path()?,
+    };
+
+    match PrivKey::from_file_with_passphrase(&path, || passphrase(&path, passphrase_file)) {
+        Ok(priv_key) => Ok(Identity::Key(priv_key)),
+        Err(source) => Err(SessionError::Identity { path, source }),
+    }
+}
+
+/// Picks the `ssh-agent` identity whose `SHA256:` fingerprint or comment is `selector`, or the
+/// first one the agent lists.
+#[cfg(unix)]
+pub fn from_agent(selector: Option<&str>) -> Result<Identity, SessionError> {
+    let mut agent = Agent::connect()?;
+    let mut keys = agent.identities()?.into_iter();
+
+    let key = match selector {
+        Some(selector) => keys
+            .find(|key| {
+                key.comment() == selector
+                    || key
+                        .pub_key()
+                        .fingerprint()
+                        .is_ok_and(|fingerprint| fingerprint == selector)
+            })
+            .ok_or_else(|| SessionError::AgentKeyNotFound(selector.to_string()))?,
+        None => keys.next().ok_or(SessionError::NoAgentKeys)?,
+    };
+
+    Ok(Identity::Agent { agent, key })
+}
+
+#[cfg(not(unix))]
+pub fn from_agent(_selector: Option<&str>) -> Result<Identity, SessionError> {
+    Err(SessionError::AgentNotSupported)
+}
+
+/// Prints the `ssh-agent` identities cliplink can use, one `fingerprint comment` line each.
+#[cfg(unix)]
+pub fn print_agent_keys() -> Result<(), SessionError> {
+    for key in Agent::connect()?.identities()? {
+        println!("{}\t{}", key.pub_key().fingerprint()?, key.comment());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn print_agent_keys() -> Result<(), SessionError> {
+    Err(SessionError::AgentNotSupported)
+}
+
+fn passphrase(
+    path: &Path,
+    passphrase_file: Option<PathBuf>,
+) -> Result<Zeroizing<String>, RsaError> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(Zeroizing::new(passphrase));
+    }
+
+    let passphrase_file = std::env::var_os(PASSPHRASE_FILE_ENV)
+        .map(PathBuf::from)
+        .or(passphrase_file);
+
+    match passphrase_file {
+        Some(file) => read_passphrase_file(&file),
+        None => rpassword::prompt_password(format!("Enter passphrase for {}: ", path.display()))
+            .map(Zeroizing::new)
+            .map_err(|err| RsaError::Passphrase(err.to_string())),
+    }
+}
+
+fn read_passphrase_file(file: &Path) -> Result<Zeroizing<String>, RsaError> {
+    let contents = Zeroizing::new(
+        std::fs::read_to_string(file)
+            .map_err(|err| RsaError::Passphrase(format!("{file:?}: {err}")))?,
+    );
+
+    Ok(Zeroizing::new(
+        contents.lines().next().unwrap_or_default().to_string(),
+    ))
+}
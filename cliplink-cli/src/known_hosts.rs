@@ -0,0 +1,169 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use cliplink_common::Config;
+use cliplink_crypto::{PubKey, RsaError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum KnownHostsError {
+    #[error(
+        "WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!\n\
+         The host key for {host} is now {fingerprint}, which does not match the one pinned in \
+         {path:?}.\n\
+         Someone could be impersonating the server. If the key was rotated on purpose, remove \
+         the {host} entry from {path:?} and connect again."
+    )]
+    HostKeyChanged {
+        host: String,
+        fingerprint: String,
+        path: PathBuf,
+    },
+
+    #[error(
+        "WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!\n\
+         The host key for {host} is {fingerprint}, not the {pinned} pinned by the profile.\n\
+         Someone could be impersonating the server. If the key was rotated on purpose, update \
+         the profile server-key."
+    )]
+    PinnedKeyMismatch {
+        host: String,
+        fingerprint: String,
+        pinned: String,
+    },
+
+    #[error("no user config directory for known_hosts, set HOME or XDG_CONFIG_HOME")]
+    NoConfigDir,
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+
+    #[error(transparent)]
+    RsaError(#[from] RsaError),
+}
+
+/// How server host keys are trusted.
+pub enum KnownHosts {
+    /// Host keys pinned on first use, one `host:port <openssh public key>` entry per line.
+    File(PathBuf),
+
+    /// Only the host key with this SHA-256 fingerprint, pinned by the profile. No file is read
+    /// or written.
+    Pinned(String),
+}
+
+impl KnownHosts {
+    /// The per-user known hosts file, `~/.config/cliplink/known_hosts`. It is created on the
+    /// first connection.
+    pub fn user_file() -> Result<Self, KnownHostsError> {
+        Config::user_file_path("known_hosts")
+            .map(Self::File)
+            .ok_or(KnownHostsError::NoConfigDir)
+    }
+
+    /// Checks `host_key` against the key pinned for `host`, pinning it if `host` is unknown.
+    pub fn verify(&self, host: &str, host_key: &PubKey) -> Result<(), KnownHostsError> {
+        let path = match self {
+            Self::File(path) => path,
+            Self::Pinned(pinned) => {
+                let fingerprint = host_key.fingerprint()?;
+
+                return match fingerprint == *pinned {
+                    true => Ok(()),
+                    false => Err(KnownHostsError::PinnedKeyMismatch {
+                        host: host.to_string(),
+                        fingerprint,
+                        pinned: pinned.clone(),
+                    }),
+                };
+            }
+        };
+
+        let host_key_openssh = host_key.to_openssh(None)?;
+
+        match lookup(path, host)? {
+            Some(pinned) if pinned == host_key_openssh => Ok(()),
+            Some(_) => Err(KnownHostsError::HostKeyChanged {
+                host: host.to_string(),
+                fingerprint: host_key.fingerprint()?,
+                path: path.clone(),
+            }),
+            None => {
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{host} {host_key_openssh}")?;
+
+                eprintln!(
+                    "permanently added {host} ({}) to the known hosts in {path:?}",
+                    host_key.fingerprint()?,
+                );
+
+                Ok(())
+            }
+        }
+    }
+}
+
+fn lookup(path: &Path, host: &str) -> Result<Option<String>, KnownHostsError> {
+    let known_hosts = match std::fs::read_to_string(path) {
+        Ok(known_hosts) => known_hosts,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(known_hosts
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(' '))
+        .find(|(entry, _)| *entry == host)
+        .map(|(_, key)| key.trim().to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use cliplink_crypto::{Ed25519PrivKey, PubKey};
+
+    use super::*;
+
+    fn gen_host_key() -> PubKey {
+        PubKey::Ed25519(Ed25519PrivKey::generate().pub_key())
+    }
+
+    #[test]
+    fn trust_on_first_use() {
+        let dir = tempfile::tempdir().unwrap();
+        // the config dir doesn't exist before the first connection
+        let known_hosts = KnownHosts::File(dir.path().join("cliplink/known_hosts"));
+
+        let host_key = gen_host_key();
+        known_hosts.verify("127.0.0.1:6166", &host_key).unwrap();
+        known_hosts.verify("127.0.0.1:6166", &host_key).unwrap();
+
+        let other_host_key = gen_host_key();
+        known_hosts
+            .verify("10.0.0.2:6166", &other_host_key)
+            .unwrap();
+        assert!(matches!(
+            known_hosts.verify("127.0.0.1:6166", &other_host_key),
+            Err(KnownHostsError::HostKeyChanged { .. })
+        ));
+    }
+
+    #[test]
+    fn pinned_key() {
+        let host_key = gen_host_key();
+        let known_hosts = KnownHosts::Pinned(host_key.fingerprint().unwrap());
+
+        known_hosts.verify("127.0.0.1:6166", &host_key).unwrap();
+        assert!(matches!(
+            known_hosts.verify("127.0.0.1:6166", &gen_host_key()),
+            Err(KnownHostsError::PinnedKeyMismatch { .. })
+        ));
+    }
+}
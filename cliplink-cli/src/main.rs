@@ -1,60 +1,353 @@
-use std::net::TcpStream;
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    process::ExitCode,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use clap::Parser;
-use cliplink_common::{PACKET_SIZE, Packet};
+use clap::{Parser, Subcommand};
+use cliplink_common::{
+    ClipName, ContentError, DEFAULT_CLIP, detect_content_type, format_duration, parse_duration,
+    validate_content_type, validate_filename,
+};
+use cliplink_crypto::{ClipKey, RsaPadding};
 
 use crate::{
-    conn::Connection,
-    session::{Session, SessionError},
+    conn::{Connection, ConnectionError},
+    identity::Identity,
+    known_hosts::KnownHosts,
+    profile::Profile,
+    session::{PasteOptions, Session, SessionError},
 };
 
 mod conn;
+mod identity;
+mod known_hosts;
+mod profile;
 mod session;
 
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 6166;
+
 /// Cliplink client
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(name = "cliplink", version, about, long_about = None)]
 struct Args {
-    /// Port to connect in the host machine
-    #[arg(short, long, default_value = "6166")]
-    port: u16,
-
-    /// Host machine address
-    #[arg(long, default_value = "127.0.0.1")]
-    host: String,
+    /// Profile from ~/.config/cliplink/config.toml to connect with [default: the default-profile
+    /// named there, else "default"]
+    #[arg(short = 'P', long, global = true)]
+    profile: Option<String>,
 
-    /// Host machine address
+    /// Port to connect in the host machine [default: 6166]
     #[arg(short, long)]
-    clip: Option<String>,
+    port: Option<u16>,
+
+    /// Host machine address [default: 127.0.0.1]
+    #[arg(long)]
+    host: Option<String>,
+
+    /// Private key to authenticate with [default: ~/.ssh/id_ed25519, else ~/.ssh/id_rsa]
+    #[arg(long, global = true)]
+    identity: Option<PathBuf>,
+
+    /// Authenticate with a key held by ssh-agent, found through SSH_AUTH_SOCK
+    #[arg(long, global = true, conflicts_with = "identity")]
+    agent: bool,
+
+    /// Fingerprint or comment of the ssh-agent key to use, as listed by `cliplink keys`
+    /// [default: the first one]. Implies --agent
+    #[arg(long, global = true, conflicts_with = "identity")]
+    agent_key: Option<String>,
+
+    /// Clip slot to use instead of the default one
+    #[arg(short, long, global = true)]
+    clip: Option<ClipName>,
+
+    /// Seal RSA session keys with PKCS#1 v1.5 instead of OAEP, for legacy servers
+    #[cfg(feature = "legacy-pkcs1v15")]
+    #[arg(long)]
+    legacy_pkcs1v15: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(flatten)]
+    Server(ServerCommand),
+
+    /// List the ssh-agent keys usable with --agent-key
+    Keys,
+}
+
+/// Commands run over a connection to the server.
+#[derive(Subcommand, Debug)]
+enum ServerCommand {
+    /// Upload a clip read from stdin, a file or the command line
+    Paste {
+        /// Read the clip from a file instead of stdin
+        #[arg(short, long, conflicts_with = "text")]
+        file: Option<PathBuf>,
+
+        /// Clip contents, instead of reading stdin
+        text: Option<String>,
+
+        /// Delete the clip after this long, e.g. 90s, 5m, 1h30m or 7d
+        #[arg(long, value_parser = parse_duration)]
+        ttl: Option<Duration>,
+
+        /// Burn after reading: the first copy deletes the clip
+        #[arg(long)]
+        once: bool,
+
+        /// MIME type of the clip, detected from its contents when absent
+        #[arg(long = "type", value_parser = parse_content_type)]
+        content_type: Option<String>,
+
+        /// Encrypt the clip with a key derived from your identity, so that the server can't read
+        /// it. Any device with the same identity can copy it. With --agent, a warning explains who
+        /// else can derive the key
+        #[arg(long)]
+        e2e: bool,
+    },
+
+    /// Write a clip to stdout, byte for byte
+    Copy {
+        /// How many pastes back in the clip history to go, 0 being the latest
+        #[arg(short, long, default_value_t = 0)]
+        index: u32,
+    },
+
+    /// List the entries kept in the clip history, latest first
+    History,
+
+    /// List your clips
+    Ls,
+
+    /// Delete a clip along with its history
+    Rm {
+        /// Clip to delete
+        clip: ClipName,
+    },
 }
 
-fn main() {
+fn main() -> ExitCode {
     let args = Args::parse();
-    let addr = args.host;
-    let port = args.port;
 
-    let bind = format!("{addr}:{port}");
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("cliplink: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Args) -> Result<(), SessionError> {
+    #[cfg(feature = "legacy-pkcs1v15")]
+    let padding = match args.legacy_pkcs1v15 {
+        true => RsaPadding::Pkcs1v15,
+        false => RsaPadding::Oaep,
+    };
+    #[cfg(not(feature = "legacy-pkcs1v15"))]
+    let padding = RsaPadding::Oaep;
+
+    let command = match args.command {
+        Command::Server(command) => command,
+        Command::Keys => return identity::print_agent_keys(),
+    };
+
+    let profile = Profile::load(args.profile.as_deref())?;
+
+    // Read the clip before connecting, so a slow producer doesn't hold the connection open.
+    let input = match &command {
+        ServerCommand::Paste {
+            file: Some(file), ..
+        } => Some(std::fs::read(file)?),
+        ServerCommand::Paste {
+            text: Some(text), ..
+        } => Some(text.clone().into_bytes()),
+        ServerCommand::Paste { .. } => {
+            let mut buf = Vec::new();
+            std::io::stdin().lock().read_to_end(&mut buf)?;
+            Some(buf)
+        }
+        ServerCommand::Copy { .. }
+        | ServerCommand::History
+        | ServerCommand::Ls
+        | ServerCommand::Rm { .. } => None,
+    };
+
+    let host = format!(
+        "{}:{}",
+        args.host
+            .or(profile.host)
+            .as_deref()
+            .unwrap_or(DEFAULT_HOST),
+        args.port.or(profile.port).unwrap_or(DEFAULT_PORT)
+    );
+    // An explicit --identity overrides a profile that uses the agent.
+    let agent =
+        args.agent || args.agent_key.is_some() || (args.identity.is_none() && profile.agent);
+    let identity = match agent {
+        true => identity::from_agent(args.agent_key.or(profile.agent_key).as_deref())?,
+        false => identity::from_file(args.identity.or(profile.identity), profile.passphrase_file)?,
+    };
+    let known_hosts = match profile.server_key {
+        Some(fingerprint) => KnownHosts::Pinned(fingerprint),
+        None => KnownHosts::user_file()?,
+    };
+
+    let mut session = connect(&host, identity, padding, &known_hosts)?;
+    let clip = args.clip.or(profile.clip);
+    let clip = clip.as_ref();
+    // End-to-end encrypted clips are bound to the name the server keeps them under.
+    let clip_name = clip.map_or(DEFAULT_CLIP, ClipName::as_str);
+
+    match (command, input) {
+        (
+            ServerCommand::Paste {
+                file,
+                ttl,
+                once,
+                content_type,
+                e2e,
+                ..
+            },
+            input,
+        ) => {
+            let buf = input.unwrap_or_default();
+            let filename = file
+                .as_deref()
+                .and_then(|file| file.file_name())
+                .map(|filename| filename.to_string_lossy().into_owned())
+                .filter(|filename| validate_filename(filename).is_ok());
+            let content_type = content_type
+                .unwrap_or_else(|| detect_content_type(&buf, filename.as_deref()).to_string());
+            let e2e = e2e || profile.e2e;
+            let buf = match e2e {
+                true => clip_key(&mut session, agent)?.seal(&buf, clip_name, &content_type)?,
+                false => buf,
+            };
+
+            let options = PasteOptions {
+                ttl,
+                once,
+                content_type: Some(content_type),
+                filename,
+                e2e,
+            };
+            session.paste(clip, buf, &options)?
+        }
+        (ServerCommand::Copy { index }, _) => {
+            let mut clip = session.copy(clip, index)?;
+            if clip.e2e {
+                let content_type = clip.content_type.as_deref().unwrap_or_default();
+                clip.payload =
+                    clip_key(&mut session, agent)?.open(&clip.payload, clip_name, content_type)?;
+            } else if profile.e2e {
+                session.term()?;
+                return Err(SessionError::NotEncrypted);
+            }
+
+            if let Some(content_type) = &clip.content_type {
+                eprintln!("content-type: {content_type}");
+            }
+            if let Some(filename) = &clip.filename {
+                eprintln!("filename: {filename}");
+            }
+
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&clip.payload)?;
+            stdout.flush()?;
+        }
+        (ServerCommand::History, _) => {
+            let now = SystemTime::now();
 
-    let stream = TcpStream::connect(bind).expect("failed to establish connection");
+            for (index, entry) in session.history(clip)?.iter().enumerate() {
+                let age = now
+                    .duration_since(unix_time(entry.created_at))
+                    .unwrap_or_default();
 
-    handle(stream).expect("failed to handle")
+                let expiry = match entry.expires_at {
+                    Some(expires_at) => match unix_time(expires_at).duration_since(now) {
+                        Ok(left) => format!("\texpires in {}", format_duration(left)),
+                        Err(_) => "\texpired".to_string(),
+                    },
+                    None => String::new(),
+                };
+
+                println!(
+                    "{index}\t{} bytes\t{} ago{expiry}{}{}",
+                    entry.size,
+                    format_duration(age),
+                    if entry.once { "\tonce" } else { "" },
+                    if entry.e2e { "\te2e" } else { "" }
+                );
+            }
+        }
+        (ServerCommand::Ls, _) => {
+            let now = SystemTime::now();
+
+            for clip in session.list()? {
+                let age = now
+                    .duration_since(unix_time(clip.updated_at))
+                    .unwrap_or_default();
+
+                println!(
+                    "{}\t{} bytes\t{} entries\t{} ago\t{}",
+                    clip.name,
+                    clip.size,
+                    clip.entries,
+                    format_duration(age),
+                    clip.content_type.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        (ServerCommand::Rm { clip }, _) => session.delete(&clip)?,
+    }
+
+    session.term()
+}
+
+/// Derives the key for end-to-end encrypted clips, warning when it comes from `ssh-agent`.
+fn clip_key(session: &mut Session, agent: bool) -> Result<ClipKey, SessionError> {
+    if agent {
+        eprintln!(
+            "cliplink: warning: the e2e key is derived through ssh-agent, anyone who can use the \
+             agent, e.g. on a host it is forwarded to, can derive it and read e2e clips"
+        );
+    }
+
+    session.clip_key()
+}
+
+fn parse_content_type(content_type: &str) -> Result<String, ContentError> {
+    validate_content_type(content_type)?;
+    Ok(content_type.to_string())
 }
 
-fn handle(stream: TcpStream) -> Result<(), SessionError> {
-    let mut buf = [0u8; PACKET_SIZE];
+fn unix_time(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+fn connect(
+    host: &str,
+    identity: Identity,
+    padding: RsaPadding,
+    known_hosts: &KnownHosts,
+) -> Result<Session, SessionError> {
+    let stream = TcpStream::connect(host).map_err(ConnectionError::from)?;
     let conn = Connection::from(stream);
 
-    let mut conn = conn.send_ssh_key()?;
-    conn.read_bytes(&mut buf).unwrap(); // TODO: fix
-    let conn = conn.parse_aes256_key(&Packet::from_bytes(&buf))?;
-    let mut session = Session::new(conn);
+    let mut conn = conn.send_ssh_key(identity, padding)?;
+    let frame = conn.read_frame()?;
+    let mut conn = conn.parse_session_keys(&frame, known_hosts, host)?;
 
-    session.paste(None, b"xungoro".to_vec())?;
-    let clip = session.copy(None)?;
-    println!(
-        "{}",
-        String::from_utf8(clip).expect("failed to serialize string")
-    );
+    let frame = conn.read_frame()?;
+    let conn = conn.parse_auth_ack(&frame)?;
 
-    Ok(())
+    Ok(Session::new(conn))
 }
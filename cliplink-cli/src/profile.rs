@@ -0,0 +1,293 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use cliplink_common::{ClipName, ClipNameError, Config};
+use cliplink_crypto::{PubKey, RsaError};
+use serde::Deserialize;
+
+/// Name of the client config file, in the user config directory.
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// Profile used when neither `--profile` nor `default-profile` name one.
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProfileError {
+    #[error("failed to read {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("invalid config file {path:?}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: Box<toml::de::Error>,
+    },
+
+    #[error("no profile {name:?} in {path:?}")]
+    NotFound { name: String, path: PathBuf },
+
+    #[error("no user config directory for profile {0:?}, set HOME or XDG_CONFIG_HOME")]
+    NoConfigDir(String),
+
+    #[error("invalid clip in profile {profile:?}: {source}")]
+    InvalidClip {
+        profile: String,
+        source: ClipNameError,
+    },
+
+    #[error("invalid server key in profile {profile:?}: {source}")]
+    InvalidServerKey { profile: String, source: RsaError },
+}
+
+/// The client config file, e.g.:
+///
+/// ```toml
+/// default-profile = "work"
+///
+/// [profiles.work]
+/// host = "clip.example.com"
+/// port = 6166
+/// identity = "~/.ssh/id_work"
+/// passphrase-file = "~/.config/cliplink/id_work.pass"
+/// clip = "notes"
+/// server-key = "SHA256:4Sp8JMqLm2GFbhD+Xk0l7P2mrF2Fpi4zLD/Xlh7izvw"
+///
+/// [profiles.laptop]
+/// agent-key = "laptop@example.com"
+/// e2e = true
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ClientConfig {
+    default_profile: Option<String>,
+    profiles: BTreeMap<String, ProfileEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ProfileEntry {
+    host: Option<String>,
+    port: Option<u16>,
+    identity: Option<PathBuf>,
+    passphrase_file: Option<PathBuf>,
+    agent: Option<bool>,
+    agent_key: Option<String>,
+    e2e: Option<bool>,
+    clip: Option<String>,
+    server_key: Option<String>,
+}
+
+/// Connection settings from a profile. Command line flags override them.
+#[derive(Debug, Default)]
+pub struct Profile {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+
+    /// Private key to authenticate with, instead of `~/.ssh/id_ed25519` or `~/.ssh/id_rsa`.
+    pub identity: Option<PathBuf>,
+
+    /// File whose first line is the passphrase of an encrypted identity.
+    pub passphrase_file: Option<PathBuf>,
+
+    /// Authenticate with a key held by `ssh-agent` instead of an identity file.
+    pub agent: bool,
+
+    /// Fingerprint or comment of the `ssh-agent` key to use. Implies `agent`.
+    pub agent_key: Option<String>,
+
+    /// Encrypt pastes end to end, as `paste --e2e` does, and refuse to copy clips that aren't.
+    /// With `agent`, mind who can use the agent, see [`cliplink_crypto::CLIP_KEY_MESSAGE`].
+    pub e2e: bool,
+
+    /// Clip used when `--clip` is absent.
+    pub clip: Option<ClipName>,
+
+    /// SHA-256 fingerprint the server host key must have, trusted instead of known_hosts.
+    pub server_key: Option<String>,
+}
+
+impl Profile {
+    /// Loads profile `name` from `~/.config/cliplink/config.toml`, or the default profile when
+    /// `name` is `None`.
+    ///
+    /// A missing config file, or a missing profile named `default`, gives an empty profile. A
+    /// profile named by `--profile` or `default-profile` must exist. Without a user config
+    /// directory there is no config file, so only the empty default profile can be loaded.
+    pub fn load(name: Option<&str>) -> Result<Self, ProfileError> {
+        let Some(path) = Config::user_file_path(CONFIG_FILE) else {
+            return match name {
+                Some(name) => Err(ProfileError::NoConfigDir(name.to_string())),
+                None => Ok(Self::default()),
+            };
+        };
+
+        let toml = match std::fs::read_to_string(&path) {
+            Ok(toml) => toml,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(source) => return Err(ProfileError::Read { path, source }),
+        };
+
+        Self::from_toml(&path, &toml, name)
+    }
+
+    fn from_toml(path: &Path, toml: &str, name: Option<&str>) -> Result<Self, ProfileError> {
+        let mut config: ClientConfig =
+            toml::from_str(toml).map_err(|source| ProfileError::Parse {
+                path: path.to_path_buf(),
+                source: Box::new(source),
+            })?;
+
+        let name = name
+            .or(config.default_profile.as_deref())
+            .unwrap_or(DEFAULT_PROFILE)
+            .to_string();
+        let required = name != DEFAULT_PROFILE || config.default_profile.is_some();
+
+        let entry = match config.profiles.remove(&name) {
+            Some(entry) => entry,
+            None if required => {
+                return Err(ProfileError::NotFound {
+                    name,
+                    path: path.to_path_buf(),
+                });
+            }
+            None => return Ok(Self::default()),
+        };
+
+        let clip = entry
+            .clip
+            .map(|clip| ClipName::new(&clip))
+            .transpose()
+            .map_err(|source| ProfileError::InvalidClip {
+                profile: name.clone(),
+                source,
+            })?;
+
+        let server_key = entry
+            .server_key
+            .map(|server_key| server_key_fingerprint(&server_key))
+            .transpose()
+            .map_err(|source| ProfileError::InvalidServerKey {
+                profile: name.clone(),
+                source,
+            })?;
+
+        Ok(Self {
+            host: entry.host,
+            port: entry.port,
+            identity: entry.identity.map(|identity| expand_home(&identity)),
+            passphrase_file: entry.passphrase_file.map(|file| expand_home(&file)),
+            agent: entry.agent.unwrap_or_default() || entry.agent_key.is_some(),
+            agent_key: entry.agent_key,
+            e2e: entry.e2e.unwrap_or_default(),
+            clip,
+            server_key,
+        })
+    }
+}
+
+/// Accepts a server key either as its `SHA256:` fingerprint, as printed by the server on start,
+/// or as an OpenSSH public key.
+fn server_key_fingerprint(server_key: &str) -> Result<String, RsaError> {
+    let server_key = server_key.trim();
+
+    match server_key.starts_with("SHA256:") {
+        true => Ok(server_key.to_string()),
+        false => PubKey::from_openssh(server_key.as_bytes())?.fingerprint(),
+    }
+}
+
+/// Expands a leading `~` to the home directory.
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::home_dir()) {
+        (Ok(path), Some(home)) => home.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [profiles.default]
+        host = "10.0.0.2"
+
+        [profiles.work]
+        host = "clip.example.com"
+        port = 7000
+        identity = "/keys/id_work"
+        passphrase-file = "/keys/id_work.pass"
+        clip = "notes"
+        server-key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILM+rvN+ot98qgEN796jTiQfZfG1KaT0PtFDJ/XFSqti"
+
+        [profiles.laptop]
+        agent-key = "laptop@example.com"
+        e2e = true
+    "#;
+
+    fn load(toml: &str, name: Option<&str>) -> Result<Profile, ProfileError> {
+        Profile::from_toml(Path::new(CONFIG_FILE), toml, name)
+    }
+
+    #[test]
+    fn selects_profiles() {
+        let profile = load(CONFIG, Some("work")).unwrap();
+        assert_eq!(profile.host.as_deref(), Some("clip.example.com"));
+        assert_eq!(profile.port, Some(7000));
+        assert_eq!(profile.identity, Some(PathBuf::from("/keys/id_work")));
+        assert_eq!(
+            profile.passphrase_file,
+            Some(PathBuf::from("/keys/id_work.pass"))
+        );
+        assert_eq!(profile.clip, Some(ClipName::new("notes").unwrap()));
+        assert!(profile.server_key.unwrap().starts_with("SHA256:"));
+
+        assert!(!profile.agent);
+
+        let profile = load(CONFIG, Some("laptop")).unwrap();
+        assert!(profile.agent);
+        assert_eq!(profile.agent_key.as_deref(), Some("laptop@example.com"));
+        assert!(profile.e2e);
+
+        let profile = load(CONFIG, None).unwrap();
+        assert_eq!(profile.host.as_deref(), Some("10.0.0.2"));
+
+        let profile = load(&format!("default-profile = \"work\"\n{CONFIG}"), None).unwrap();
+        assert_eq!(profile.port, Some(7000));
+
+        let profile = load("", None).unwrap();
+        assert!(profile.host.is_none());
+    }
+
+    #[test]
+    fn rejects_missing_and_invalid_profiles() {
+        assert!(matches!(
+            load(CONFIG, Some("home")),
+            Err(ProfileError::NotFound { .. })
+        ));
+        assert!(matches!(
+            load("default-profile = \"home\"", None),
+            Err(ProfileError::NotFound { .. })
+        ));
+        assert!(matches!(
+            load("[profiles.default]\nclip = \".hidden\"", None),
+            Err(ProfileError::InvalidClip { .. })
+        ));
+        assert!(matches!(
+            load(
+                "[profiles.default]\nserver-key = \"ssh-ed25519 AAAA\"",
+                None
+            ),
+            Err(ProfileError::InvalidServerKey { .. })
+        ));
+        assert!(matches!(
+            load("[profiles.default]\nhots = \"10.0.0.2\"", None),
+            Err(ProfileError::Parse { .. })
+        ));
+    }
+}
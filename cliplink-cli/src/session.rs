@@ -1,17 +1,112 @@
-use cliplink_common::{Packet, PacketError};
+use std::{path::PathBuf, time::Duration};
 
-use crate::conn::{Connection, ConnectionError, Secure};
+use cliplink_common::{
+    CHUNK_SIZE, ClipName, CodecError, Decoder, Encoder, Frame, HistoryEntry, Transfer,
+    TransferError, TransferHeader,
+};
+use cliplink_crypto::ClipKey;
+
+use crate::{
+    conn::{Connection, ConnectionError, Secure},
+    known_hosts::KnownHostsError,
+    profile::ProfileError,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum SessionError {
     #[error("wrong response {0:?}")]
     WrongResponse(String),
 
+    #[error("denied: {0}")]
+    Denied(String),
+
+    #[error("failed to load identity {path:?}: {source}")]
+    Identity {
+        path: PathBuf,
+        source: cliplink_crypto::RsaError,
+    },
+
+    #[error("no ssh-agent identity has fingerprint or comment {0:?}")]
+    AgentKeyNotFound(String),
+
+    #[error("ssh-agent holds no identity")]
+    NoAgentKeys,
+
+    #[cfg(not(unix))]
+    #[error("ssh-agent is not supported on this platform")]
+    AgentNotSupported,
+
+    #[error("clip is not end-to-end encrypted, which the profile requires")]
+    NotEncrypted,
+
     #[error(transparent)]
     ConnectionError(#[from] ConnectionError),
 
     #[error(transparent)]
-    PacketError(#[from] PacketError),
+    KnownHosts(#[from] KnownHostsError),
+
+    #[error(transparent)]
+    TransferError(#[from] TransferError),
+
+    #[error(transparent)]
+    CodecError(#[from] CodecError),
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+
+    #[error(transparent)]
+    ProfileError(#[from] ProfileError),
+
+    #[error(transparent)]
+    RsaError(#[from] cliplink_crypto::RsaError),
+
+    #[cfg(unix)]
+    #[error(transparent)]
+    AgentError(#[from] cliplink_crypto::AgentError),
+
+    #[error(transparent)]
+    E2eError(#[from] cliplink_crypto::E2eError),
+}
+
+/// A clip as fetched by `copy`.
+#[derive(Debug)]
+pub struct Clip {
+    pub payload: Vec<u8>,
+    pub content_type: Option<String>,
+    pub filename: Option<String>,
+
+    /// `payload` is end-to-end encrypted, see [`cliplink_crypto::ClipKey`].
+    pub e2e: bool,
+}
+
+/// How the server should keep a pasted clip.
+#[derive(Debug, Default)]
+pub struct PasteOptions {
+    /// Delete the clip after this long. The server may cap it to a shorter TTL.
+    pub ttl: Option<Duration>,
+
+    /// Delete the clip on its first copy.
+    pub once: bool,
+
+    pub content_type: Option<String>,
+    pub filename: Option<String>,
+
+    /// The payload is end-to-end encrypted, so the server keeps it as opaque ciphertext.
+    pub e2e: bool,
+}
+
+/// A clip slot, as listed by `list`.
+#[derive(Debug)]
+pub struct ClipInfo {
+    pub name: String,
+    pub size: u64,
+    pub content_type: Option<String>,
+
+    /// Seconds since the Unix epoch.
+    pub updated_at: u64,
+
+    /// Entries kept in the history.
+    pub entries: u32,
 }
 
 pub struct Session(Connection<Secure>);
@@ -21,27 +116,162 @@ impl Session {
         Self(conn)
     }
 
-    pub fn copy(&mut self, _clip: Option<&String>) -> Result<Vec<u8>, SessionError> {
-        self.0.write_packet_sec(Packet::new(b"copy", &[]))?;
-        let packet = self.0.read_packet_sec()?;
+    /// Derives the key for end-to-end encrypted clips from the identity the session was
+    /// authenticated with, see [`crate::identity::Identity::clip_key`].
+    pub fn clip_key(&mut self) -> Result<ClipKey, SessionError> {
+        self.0.identity_mut().clip_key()
+    }
 
-        match packet.ty()? {
-            b"copyack" => Ok(packet.payload()?.to_vec()),
-            ty => Err(SessionError::WrongResponse(
-                str::from_utf8(ty).unwrap_or_default().to_string(),
-            )),
+    /// Fetches the entry `index` pastes back in the history of `clip`, `0` being the latest.
+    pub fn copy(&mut self, clip: Option<&ClipName>, index: u32) -> Result<Clip, SessionError> {
+        let payload = Encoder::default()
+            .put_str(ClipName::to_wire(clip))
+            .put_u32(index)
+            .finish();
+        self.0.write_packet_sec(Frame::new(b"copy", &payload))?;
+        let frame = self.0.read_packet_sec()?;
+
+        match frame.ty.as_slice() {
+            b"copybegin" => {}
+            b"copydeny" => return Err(denied(&frame)),
+            ty => return Err(wrong_response(ty)),
+        }
+
+        let mut decoder = Decoder::new(&frame.payload);
+        let mut transfer =
+            Transfer::new(TransferHeader::from_bytes(decoder.get_bytes()?)?, u64::MAX)?;
+        let content_type = optional_str(decoder.get_str()?);
+        let filename = optional_str(decoder.get_str()?);
+        let e2e = decoder.get_u8()? != 0;
+        decoder.finish()?;
+
+        loop {
+            let frame = self.0.read_packet_sec()?;
+
+            match frame.ty.as_slice() {
+                b"copychunk" => transfer.push(&frame.payload)?,
+                b"copycommit" => {
+                    return Ok(Clip {
+                        payload: transfer.finish()?,
+                        content_type,
+                        filename,
+                        e2e,
+                    });
+                }
+                ty => return Err(wrong_response(ty)),
+            }
         }
     }
 
-    pub fn paste(&mut self, _clip: Option<&String>, buf: Vec<u8>) -> Result<(), SessionError> {
-        self.0.write_packet_sec(Packet::new(b"paste", &buf))?;
-        let packet = self.0.read_packet_sec()?;
+    /// Uploads `buf` as the latest entry of `clip`.
+    pub fn paste(
+        &mut self,
+        clip: Option<&ClipName>,
+        buf: Vec<u8>,
+        options: &PasteOptions,
+    ) -> Result<(), SessionError> {
+        let payload = Encoder::default()
+            .put_str(ClipName::to_wire(clip))
+            .put_bytes(&TransferHeader::new(&buf).to_bytes())
+            .put_u64(options.ttl.map_or(0, |ttl| ttl.as_secs().max(1)))
+            .put_u8(options.once as u8)
+            .put_str(options.content_type.as_deref().unwrap_or_default())
+            .put_str(options.filename.as_deref().unwrap_or_default())
+            .put_u8(options.e2e as u8)
+            .finish();
+        self.0
+            .write_packet_sec(Frame::new(b"pastebegin", &payload))?;
+        self.expect_ack(b"pastebeginack")?;
 
-        match packet.ty()? {
-            b"pasteack" => Ok(()),
-            ty => Err(SessionError::WrongResponse(
-                str::from_utf8(ty).unwrap_or_default().to_string(),
-            )),
+        for chunk in buf.chunks(CHUNK_SIZE) {
+            self.0.write_packet_sec(Frame::new(b"pastechunk", chunk))?;
         }
+
+        self.0.write_packet_sec(Frame::new(b"pastecommit", &[]))?;
+        self.expect_ack(b"pasteack")
+    }
+
+    /// Lists the history of `clip`, latest first.
+    pub fn history(&mut self, clip: Option<&ClipName>) -> Result<Vec<HistoryEntry>, SessionError> {
+        let payload = Encoder::default().put_str(ClipName::to_wire(clip)).finish();
+        self.0.write_packet_sec(Frame::new(b"history", &payload))?;
+        let frame = self.0.read_packet_sec()?;
+
+        match frame.ty.as_slice() {
+            b"historyack" => {}
+            b"historydeny" => return Err(denied(&frame)),
+            ty => return Err(wrong_response(ty)),
+        }
+
+        Ok(HistoryEntry::list_from_bytes(&frame.payload)?)
+    }
+
+    /// Lists the clips of the authenticated identity, by name.
+    pub fn list(&mut self) -> Result<Vec<ClipInfo>, SessionError> {
+        self.0.write_packet_sec(Frame::new(b"list", &[]))?;
+        let frame = self.0.read_packet_sec()?;
+
+        if frame.ty != b"listack" {
+            return Err(wrong_response(&frame.ty));
+        }
+
+        let mut decoder = Decoder::new(&frame.payload);
+        let clips = (0..decoder.get_u32()?)
+            .map(|_| {
+                Ok(ClipInfo {
+                    name: decoder.get_str()?.to_string(),
+                    size: decoder.get_u64()?,
+                    content_type: optional_str(decoder.get_str()?),
+                    updated_at: decoder.get_u64()?,
+                    entries: decoder.get_u32()?,
+                })
+            })
+            .collect::<Result<_, CodecError>>()?;
+        decoder.finish()?;
+
+        Ok(clips)
     }
+
+    /// Deletes `clip` along with its history.
+    pub fn delete(&mut self, clip: &ClipName) -> Result<(), SessionError> {
+        let payload = Encoder::default().put_str(clip.as_str()).finish();
+        self.0.write_packet_sec(Frame::new(b"delete", &payload))?;
+        let frame = self.0.read_packet_sec()?;
+
+        match frame.ty.as_slice() {
+            b"deleteack" => Ok(()),
+            b"deletedeny" => Err(denied(&frame)),
+            ty => Err(wrong_response(ty)),
+        }
+    }
+
+    /// Ends the session, letting the server release the connection.
+    pub fn term(mut self) -> Result<(), SessionError> {
+        Ok(self.0.write_packet_sec(Frame::new(b"term", &[]))?)
+    }
+
+    fn expect_ack(&mut self, ack: &[u8]) -> Result<(), SessionError> {
+        let frame = self.0.read_packet_sec()?;
+
+        match frame.ty.as_slice() {
+            ty if ty == ack => Ok(()),
+            b"pastedeny" => Err(denied(&frame)),
+            ty => Err(wrong_response(ty)),
+        }
+    }
+}
+
+/// Decodes a string field where empty means unset.
+fn optional_str(value: &str) -> Option<String> {
+    Some(value)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+}
+
+fn denied(frame: &Frame) -> SessionError {
+    SessionError::Denied(String::from_utf8_lossy(&frame.payload).to_string())
+}
+
+fn wrong_response(ty: &[u8]) -> SessionError {
+    SessionError::WrongResponse(str::from_utf8(ty).unwrap_or_default().to_string())
 }
@@ -0,0 +1,56 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{Frame, FrameError, check_frame_len, decode_frame, encode_frame};
+
+/// Reads exactly one length-delimited frame, like [`crate::read_frame`] on an async stream.
+pub async fn read_frame_async<R: AsyncRead + Unpin>(r: &mut R) -> Result<Frame, FrameError> {
+    let mut len_buf = [0u8; 4];
+    r.read_exact(&mut len_buf).await?;
+    let frame_len = check_frame_len(len_buf)?;
+
+    let mut buf = vec![0u8; frame_len];
+    r.read_exact(&mut buf).await?;
+
+    decode_frame(&buf)
+}
+
+/// Writes exactly one length-delimited frame, like [`crate::write_frame`] on an async stream.
+pub async fn write_frame_async<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    frame: &Frame,
+) -> Result<(), FrameError> {
+    let buf = encode_frame(frame)?;
+    w.write_all(&buf).await?;
+    w.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{read_frame, write_frame};
+
+    #[tokio::test]
+    async fn interoperates_with_blocking_frames() {
+        let frame = Frame::new(b"paste", b"clip");
+
+        let mut wire = Vec::new();
+        write_frame_async(&mut wire, &frame).await.unwrap();
+        assert_eq!(read_frame(&mut wire.as_slice()).unwrap(), frame);
+
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &frame).unwrap();
+        assert_eq!(read_frame_async(&mut wire.as_slice()).await.unwrap(), frame);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_frames() {
+        let wire = u32::MAX.to_be_bytes();
+
+        assert!(matches!(
+            read_frame_async(&mut wire.as_slice()).await,
+            Err(FrameError::FrameTooLarge { .. })
+        ));
+    }
+}
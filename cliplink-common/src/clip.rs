@@ -0,0 +1,123 @@
+use std::{fmt, str::FromStr};
+
+/// Longest clip name accepted, in bytes.
+pub const MAX_CLIP_NAME_LEN: usize = 64;
+
+/// Name under which clips pasted without an explicit name are stored.
+pub const DEFAULT_CLIP: &str = "default";
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ClipNameError {
+    #[error("clip name is empty")]
+    Empty,
+
+    #[error("clip name too long: {len} characters (max {max})")]
+    TooLong { len: usize, max: usize },
+
+    #[error("invalid character {0:?} in clip name, expected [A-Za-z0-9._-]")]
+    InvalidChar(char),
+
+    #[error("clip name can't start with '.'")]
+    LeadingDot,
+}
+
+/// Name of a clip slot: 1 to [`MAX_CLIP_NAME_LEN`] characters out of `[A-Za-z0-9._-]`, not
+/// starting with a dot, so that it is always safe to use as a file name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClipName(String);
+
+impl ClipName {
+    pub fn new(name: &str) -> Result<Self, ClipNameError> {
+        if name.is_empty() {
+            return Err(ClipNameError::Empty);
+        }
+
+        if name.len() > MAX_CLIP_NAME_LEN {
+            return Err(ClipNameError::TooLong {
+                len: name.len(),
+                max: MAX_CLIP_NAME_LEN,
+            });
+        }
+
+        if let Some(ch) = name
+            .chars()
+            .find(|ch| !ch.is_ascii_alphanumeric() && !matches!(ch, '.' | '_' | '-'))
+        {
+            return Err(ClipNameError::InvalidChar(ch));
+        }
+
+        if name.starts_with('.') {
+            return Err(ClipNameError::LeadingDot);
+        }
+
+        Ok(Self(name.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Decodes an optional clip name as sent on the wire, where the empty string stands for the
+    /// default clip.
+    pub fn from_wire(name: &str) -> Result<Option<Self>, ClipNameError> {
+        match name {
+            "" => Ok(None),
+            name => Self::new(name).map(Some),
+        }
+    }
+
+    /// Encodes an optional clip name for the wire, see [`ClipName::from_wire`].
+    pub fn to_wire(name: Option<&Self>) -> &str {
+        name.map(Self::as_str).unwrap_or_default()
+    }
+}
+
+impl FromStr for ClipName {
+    type Err = ClipNameError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::new(name)
+    }
+}
+
+impl fmt::Display for ClipName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validates_names() {
+        assert_eq!(ClipName::new("work").unwrap().as_str(), "work");
+        assert_eq!(ClipName::new("v1.2_rc-3").unwrap().to_string(), "v1.2_rc-3");
+        assert_eq!(ClipName::new("").unwrap_err(), ClipNameError::Empty);
+        assert_eq!(
+            ClipName::new(&"a".repeat(65)).unwrap_err(),
+            ClipNameError::TooLong { len: 65, max: 64 }
+        );
+        assert_eq!(
+            ClipName::new("../etc").unwrap_err(),
+            ClipNameError::InvalidChar('/')
+        );
+        assert_eq!(
+            ClipName::new("wörk").unwrap_err(),
+            ClipNameError::InvalidChar('ö')
+        );
+        assert_eq!(ClipName::new("..").unwrap_err(), ClipNameError::LeadingDot);
+    }
+
+    #[test]
+    fn wire_roundtrip() {
+        let name = ClipName::new("work").unwrap();
+
+        assert_eq!(ClipName::from_wire(ClipName::to_wire(None)).unwrap(), None);
+        assert_eq!(
+            ClipName::from_wire(ClipName::to_wire(Some(&name))).unwrap(),
+            Some(name)
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use std::str::Utf8Error;
+
+/// Errors raised while decoding message fields.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum CodecError {
+    #[error("unexpected end of message")]
+    UnexpectedEof,
+
+    #[error("trailing bytes in message")]
+    TrailingBytes,
+
+    #[error(transparent)]
+    Utf8Error(#[from] Utf8Error),
+}
+
+/// Builds message payloads out of big-endian integers and u32 length-prefixed byte strings.
+#[derive(Debug, Default)]
+pub struct Encoder(Vec<u8>);
+
+impl Encoder {
+    pub fn put_u8(&mut self, value: u8) -> &mut Self {
+        self.0.push(value);
+        self
+    }
+
+    pub fn put_u16(&mut self, value: u16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn put_u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn put_u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn put_bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.put_u32(value.len() as u32);
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    pub fn put_str(&mut self, value: &str) -> &mut Self {
+        self.put_bytes(value.as_bytes())
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.0)
+    }
+}
+
+/// Reads fields written by [`Encoder`], in the same order.
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CodecError> {
+        if self.buf.len() - self.pos < len {
+            return Err(CodecError::UnexpectedEof);
+        }
+
+        let slice = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], CodecError> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, CodecError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16, CodecError> {
+        Ok(u16::from_be_bytes(self.take_array()?))
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, CodecError> {
+        Ok(u32::from_be_bytes(self.take_array()?))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, CodecError> {
+        Ok(u64::from_be_bytes(self.take_array()?))
+    }
+
+    pub fn get_bytes(&mut self) -> Result<&'a [u8], CodecError> {
+        let len = self.get_u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn get_str(&mut self) -> Result<&'a str, CodecError> {
+        Ok(str::from_utf8(self.get_bytes()?)?)
+    }
+
+    /// Whether the whole message was consumed, for messages whose trailing fields are optional.
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    /// Ensures the whole message was consumed.
+    pub fn finish(&self) -> Result<(), CodecError> {
+        if self.pos != self.buf.len() {
+            return Err(CodecError::TrailingBytes);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let buf = Encoder::default()
+            .put_u8(1)
+            .put_u16(2)
+            .put_u32(3)
+            .put_u64(4)
+            .put_bytes(b"public keypair")
+            .put_str("clip")
+            .finish();
+
+        let mut decoder = Decoder::new(&buf);
+        assert_eq!(decoder.get_u8().unwrap(), 1);
+        assert_eq!(decoder.get_u16().unwrap(), 2);
+        assert_eq!(decoder.get_u32().unwrap(), 3);
+        assert_eq!(decoder.get_u64().unwrap(), 4);
+        assert_eq!(decoder.get_bytes().unwrap(), b"public keypair");
+        assert_eq!(decoder.get_str().unwrap(), "clip");
+        decoder.finish().unwrap();
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        let buf = Encoder::default().put_bytes(b"public keypair").finish();
+
+        assert_eq!(
+            Decoder::new(&buf[..8]).get_bytes().unwrap_err(),
+            CodecError::UnexpectedEof
+        );
+
+        let mut decoder = Decoder::new(&buf);
+        decoder.get_u32().unwrap();
+        assert_eq!(decoder.finish().unwrap_err(), CodecError::TrailingBytes);
+    }
+}
@@ -30,9 +30,47 @@ impl Config {
         compile_error!("Unsupported target OS for system_config_dir()");
     }
 
+    /// Per-user config directory: `%APPDATA%` on Windows, `$XDG_CONFIG_HOME` or `~/.config`
+    /// elsewhere. `None` if the environment doesn't say where home is.
+    fn user_config_dir() -> Option<PathBuf> {
+        use std::env;
+        #[cfg(target_os = "windows")]
+        {
+            env::var_os("APPDATA").map(PathBuf::from)
+        }
+
+        #[cfg(not(target_os = "windows"))]
+        {
+            env::var_os("XDG_CONFIG_HOME")
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        }
+    }
+
+    /// Per-user data directory: `%LOCALAPPDATA%` on Windows, `$XDG_DATA_HOME` or
+    /// `~/.local/share` elsewhere. `None` if the environment doesn't say where home is.
+    fn user_data_dir() -> Option<PathBuf> {
+        use std::env;
+        #[cfg(target_os = "windows")]
+        {
+            env::var_os("LOCALAPPDATA").map(PathBuf::from)
+        }
+
+        #[cfg(not(target_os = "windows"))]
+        {
+            env::var_os("XDG_DATA_HOME")
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .or_else(|| {
+                    env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share"))
+                })
+        }
+    }
+
     pub fn dir_path() -> PathBuf {
         let mut config_path = Self::system_config_dir();
-        config_path.push("/cliplink");
+        config_path.push("cliplink");
 
         if !config_path.exists() {
             std::fs::create_dir(&config_path).expect("failed to create config path");
@@ -43,7 +81,6 @@ impl Config {
 
     pub fn file_path(file_name: &str) -> PathBuf {
         let mut config_path = Self::dir_path();
-        config_path.push("/");
         config_path.push(file_name);
 
         if !config_path.exists() {
@@ -52,4 +89,19 @@ impl Config {
 
         config_path
     }
+
+    /// Path of `file_name` in the system config directory, which may not exist.
+    pub fn system_file_path(file_name: &str) -> PathBuf {
+        Self::system_config_dir().join("cliplink").join(file_name)
+    }
+
+    /// Path of `file_name` in the user config directory, which may not exist.
+    pub fn user_file_path(file_name: &str) -> Option<PathBuf> {
+        Self::user_config_dir().map(|dir| dir.join("cliplink").join(file_name))
+    }
+
+    /// Path of `file_name` in the user data directory, which may not exist.
+    pub fn user_data_path(file_name: &str) -> Option<PathBuf> {
+        Self::user_data_dir().map(|dir| dir.join("cliplink").join(file_name))
+    }
 }
@@ -0,0 +1,162 @@
+/// Longest content type accepted, in bytes.
+pub const MAX_CONTENT_TYPE_LEN: usize = 255;
+
+/// Longest filename accepted, in bytes.
+pub const MAX_FILENAME_LEN: usize = 255;
+
+/// Content type of clips that are neither recognized nor UTF-8 text.
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Content type of UTF-8 text clips.
+pub const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ContentError {
+    #[error("invalid content type {0:?}, expected e.g. text/plain or image/png")]
+    InvalidContentType(String),
+
+    #[error("invalid filename {0:?}")]
+    InvalidFilename(String),
+}
+
+/// Checks that `content_type` looks like a MIME type: `type/subtype`, optionally followed by
+/// `; parameters`, in printable ASCII.
+pub fn validate_content_type(content_type: &str) -> Result<(), ContentError> {
+    let invalid = || ContentError::InvalidContentType(content_type.to_string());
+
+    if content_type.len() > MAX_CONTENT_TYPE_LEN
+        || !content_type
+            .bytes()
+            .all(|byte| byte.is_ascii_graphic() || byte == b' ')
+    {
+        return Err(invalid());
+    }
+
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    let token = |part: &str| {
+        !part.is_empty()
+            && part
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&byte))
+    };
+
+    match essence.split_once('/') {
+        Some((ty, subtype)) if token(ty) && token(subtype) => Ok(()),
+        _ => Err(invalid()),
+    }
+}
+
+/// Checks that `filename` is a bare file name, without any directory component.
+pub fn validate_filename(filename: &str) -> Result<(), ContentError> {
+    if filename.is_empty()
+        || filename.len() > MAX_FILENAME_LEN
+        || matches!(filename, "." | "..")
+        || filename
+            .chars()
+            .any(|ch| ch.is_control() || matches!(ch, '/' | '\\'))
+    {
+        return Err(ContentError::InvalidFilename(filename.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Guesses the content type of `buf` from its leading magic bytes, then from the extension of
+/// `filename`, then by whether it is UTF-8 text.
+pub fn detect_content_type(buf: &[u8], filename: Option<&str>) -> &'static str {
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+    ];
+
+    const EXTENSIONS: &[(&str, &str)] = &[
+        ("html", "text/html; charset=utf-8"),
+        ("htm", "text/html; charset=utf-8"),
+        ("md", "text/markdown; charset=utf-8"),
+        ("csv", "text/csv; charset=utf-8"),
+        ("json", "application/json"),
+        ("svg", "image/svg+xml"),
+        ("webp", "image/webp"),
+    ];
+
+    if let Some((_, content_type)) = MAGIC.iter().find(|(magic, _)| buf.starts_with(magic)) {
+        return content_type;
+    }
+
+    if buf.len() >= 12 && &buf[..4] == b"RIFF" && &buf[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+
+    let extension = filename
+        .and_then(|filename| filename.rsplit_once('.'))
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+    if let Some((_, content_type)) = EXTENSIONS
+        .iter()
+        .find(|(ext, _)| extension.as_deref() == Some(*ext))
+    {
+        return content_type;
+    }
+
+    match str::from_utf8(buf) {
+        Ok(text) => {
+            let head = text.trim_start().get(..14).unwrap_or_default();
+            if head.eq_ignore_ascii_case("<!doctype html") {
+                "text/html; charset=utf-8"
+            } else {
+                TEXT_PLAIN
+            }
+        }
+        Err(_) => OCTET_STREAM,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validates_content_types() {
+        assert!(validate_content_type("text/plain").is_ok());
+        assert!(validate_content_type("text/plain; charset=utf-8").is_ok());
+        assert!(validate_content_type("application/vnd.api+json").is_ok());
+        assert!(validate_content_type("text").is_err());
+        assert!(validate_content_type("text/").is_err());
+        assert!(validate_content_type("text/plain\n").is_err());
+        assert!(validate_content_type(&format!("text/{}", "a".repeat(255))).is_err());
+    }
+
+    #[test]
+    fn validates_filenames() {
+        assert!(validate_filename("notes.txt").is_ok());
+        assert!(validate_filename("résumé.pdf").is_ok());
+        assert!(validate_filename("").is_err());
+        assert!(validate_filename("..").is_err());
+        assert!(validate_filename("../notes.txt").is_err());
+        assert!(validate_filename("C:\\notes.txt").is_err());
+        assert!(validate_filename("notes\0.txt").is_err());
+    }
+
+    #[test]
+    fn detects_content_types() {
+        assert_eq!(
+            detect_content_type(b"\x89PNG\r\n\x1a\n....", Some("x.txt")),
+            "image/png"
+        );
+        assert_eq!(detect_content_type(b"%PDF-1.7", None), "application/pdf");
+        assert_eq!(
+            detect_content_type(b"# notes", Some("README.MD")),
+            "text/markdown; charset=utf-8"
+        );
+        assert_eq!(
+            detect_content_type(b"  <!DOCTYPE html><html></html>", None),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(detect_content_type("héllo".as_bytes(), None), TEXT_PLAIN);
+        assert_eq!(detect_content_type(b"\xff\xfe\x00", None), OCTET_STREAM);
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+const UNITS: [(&str, u64); 4] = [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)];
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum DurationError {
+    #[error("duration is empty")]
+    Empty,
+
+    #[error("invalid duration {0:?}, expected e.g. 90s, 5m, 1h30m or 7d")]
+    Invalid(String),
+}
+
+/// Parses a duration made of `<number><unit>` parts, units being `d`, `h`, `m` and `s`: `1h30m`.
+/// A bare number is in seconds.
+pub fn parse_duration(duration: &str) -> Result<Duration, DurationError> {
+    let invalid = || DurationError::Invalid(duration.to_string());
+
+    if duration.is_empty() {
+        return Err(DurationError::Empty);
+    }
+
+    if let Ok(secs) = duration.parse() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut secs = 0u64;
+    let mut rest = duration;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|ch: char| !ch.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let (value, tail) = rest.split_at(digits);
+        let value: u64 = value.parse().map_err(|_| invalid())?;
+
+        let (suffix, unit) = UNITS
+            .iter()
+            .find(|(suffix, _)| tail.starts_with(suffix))
+            .ok_or_else(invalid)?;
+
+        secs = value
+            .checked_mul(*unit)
+            .and_then(|value| secs.checked_add(value))
+            .ok_or_else(invalid)?;
+        rest = &tail[suffix.len()..];
+    }
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// Formats `duration` in its largest whole unit, truncating the rest: `90s` is `1m`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+
+    UNITS
+        .iter()
+        .find(|(_, unit)| secs >= *unit)
+        .map(|(suffix, unit)| format!("{}{suffix}", secs / unit))
+        .unwrap_or_else(|| "0s".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 86400)));
+        assert_eq!(parse_duration(""), Err(DurationError::Empty));
+        assert!(parse_duration("5").is_ok());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("5m3").is_err());
+        assert!(parse_duration("-5m").is_err());
+    }
+
+    #[test]
+    fn formats_largest_unit() {
+        assert_eq!(format_duration(Duration::ZERO), "0s");
+        assert_eq!(format_duration(Duration::from_secs(59)), "59s");
+        assert_eq!(format_duration(Duration::from_secs(90)), "1m");
+        assert_eq!(format_duration(Duration::from_secs(7200)), "2h");
+        assert_eq!(format_duration(Duration::from_secs(3 * 86400 + 5)), "3d");
+    }
+}
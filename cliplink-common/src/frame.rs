@@ -34,6 +34,18 @@ pub struct Frame {
     pub payload: Vec<u8>,
 }
 
+impl Frame {
+    pub fn new(ty: &[u8], payload: &[u8]) -> Self {
+        Self {
+            msg_type: 0,
+            flags: 0,
+            request_id: 0,
+            ty: ty.to_vec(),
+            payload: payload.to_vec(),
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum FrameError {
     /// Underlying I/O error from Read/Write.
@@ -74,9 +86,19 @@ pub fn read_frame<R: Read>(r: &mut R) -> Result<Frame, FrameError> {
     // ---- 1) Read the u32 length prefix (big-endian) ----
     let mut len_buf = [0u8; 4];
     r.read_exact(&mut len_buf)?;
+    let frame_len = check_frame_len(len_buf)?;
+
+    // ---- 2) Read the entire frame payload ----
+    let mut buf = vec![0u8; frame_len];
+    r.read_exact(&mut buf)?;
+
+    decode_frame(&buf)
+}
+
+/// Validate a u32 length prefix before allocating the frame payload it announces.
+pub(crate) fn check_frame_len(len_buf: [u8; 4]) -> Result<usize, FrameError> {
     let frame_len = u32::from_be_bytes(len_buf) as usize;
 
-    // ---- 2) Validate length before allocating ----
     if frame_len > MAX_FRAME_LEN {
         return Err(FrameError::FrameTooLarge {
             len: frame_len,
@@ -90,11 +112,12 @@ pub fn read_frame<R: Read>(r: &mut R) -> Result<Frame, FrameError> {
         });
     }
 
-    // ---- 3) Read the entire frame payload ----
-    let mut buf = vec![0u8; frame_len];
-    r.read_exact(&mut buf)?;
+    Ok(frame_len)
+}
 
-    // ---- 4) Parse fixed header ----
+/// Parse a frame payload (everything after the length prefix).
+pub(crate) fn decode_frame(buf: &[u8]) -> Result<Frame, FrameError> {
+    // ---- 1) Parse fixed header ----
     // Layout:
     // 0..4   magic
     // 4      version
@@ -118,7 +141,7 @@ pub fn read_frame<R: Read>(r: &mut R) -> Result<Frame, FrameError> {
         buf[8], buf[9], buf[10], buf[11], buf[12], buf[13], buf[14], buf[15],
     ]);
 
-    // ---- 5) Parse variable sections with bounds checks ----
+    // ---- 2) Parse variable sections with bounds checks ----
     let mut i = HEADER_LEN;
 
     // type_len: u16
@@ -169,10 +192,18 @@ pub fn read_frame<R: Read>(r: &mut R) -> Result<Frame, FrameError> {
 /// Write exactly one length-delimited frame to any `Write` (e.g., TcpStream).
 ///
 /// This function:
-/// - builds the frame payload in memory
-/// - prefixes it with a u32 length
-/// - writes both using `write_all`
+/// - builds the frame payload in memory, prefixed with its u32 length
+/// - writes both at once using `write_all`
 pub fn write_frame<W: Write>(w: &mut W, frame: &Frame) -> Result<(), FrameError> {
+    let buf = encode_frame(frame)?;
+    w.write_all(&buf)?;
+    w.flush()?; // optional; remove if you want the OS to buffer for throughput
+
+    Ok(())
+}
+
+/// Encode a frame, length prefix included.
+pub(crate) fn encode_frame(frame: &Frame) -> Result<Vec<u8>, FrameError> {
     // ---- 1) Validate sizes before encoding ----
     // Type length is u16 on-wire.
     if frame.ty.len() > u16::MAX as usize {
@@ -198,8 +229,9 @@ pub fn write_frame<W: Write>(w: &mut W, frame: &Frame) -> Result<(), FrameError>
         });
     }
 
-    // ---- 3) Allocate and build the frame payload ----
-    let mut buf = Vec::with_capacity(frame_len);
+    // ---- 3) Allocate and build the length prefix + frame payload ----
+    let mut buf = Vec::with_capacity(4 + frame_len);
+    buf.extend_from_slice(&(frame_len as u32).to_be_bytes());
 
     // Fixed header
     buf.extend_from_slice(&MAGIC);                 // 4
@@ -208,7 +240,7 @@ pub fn write_frame<W: Write>(w: &mut W, frame: &Frame) -> Result<(), FrameError>
     buf.extend_from_slice(&frame.msg_type.to_be_bytes());     // 2
     buf.extend_from_slice(&frame.request_id.to_be_bytes());   // 8
 
-    debug_assert_eq!(buf.len(), HEADER_LEN);
+    debug_assert_eq!(buf.len(), 4 + HEADER_LEN);
 
     // Variable: type_len + type bytes
     let ty_len = frame.ty.len() as u16;
@@ -220,15 +252,9 @@ pub fn write_frame<W: Write>(w: &mut W, frame: &Frame) -> Result<(), FrameError>
     buf.extend_from_slice(&payload_len.to_be_bytes());
     buf.extend_from_slice(&frame.payload);
 
-    debug_assert_eq!(buf.len(), frame_len);
+    debug_assert_eq!(buf.len(), 4 + frame_len);
 
-    // ---- 4) Write length prefix + frame payload ----
-    let len_prefix = (frame_len as u32).to_be_bytes();
-    w.write_all(&len_prefix)?;
-    w.write_all(&buf)?;
-    w.flush()?; // optional; remove if you want the OS to buffer for throughput
-
-    Ok(())
+    Ok(buf)
 }
 
 #[cfg(test)]
@@ -0,0 +1,88 @@
+use crate::{CodecError, Decoder, Encoder};
+
+/// One entry in the history of a clip, as listed in `historyack`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub size: u64,
+
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+
+    /// Seconds since the Unix epoch.
+    pub expires_at: Option<u64>,
+
+    /// Deleted by the first copy.
+    pub once: bool,
+
+    /// The payload is end-to-end encrypted, so the server keeps it as opaque ciphertext.
+    pub e2e: bool,
+}
+
+impl HistoryEntry {
+    /// Encodes a `historyack` payload: the entry count, then each entry, an expiry of 0 meaning
+    /// none.
+    pub fn list_to_bytes(history: &[Self]) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        encoder.put_u32(history.len() as u32);
+        for entry in history {
+            encoder
+                .put_u64(entry.size)
+                .put_u64(entry.created_at)
+                .put_u64(entry.expires_at.unwrap_or(0))
+                .put_u8(entry.once as u8)
+                .put_u8(entry.e2e as u8);
+        }
+
+        encoder.finish()
+    }
+
+    pub fn list_from_bytes(buf: &[u8]) -> Result<Vec<Self>, CodecError> {
+        let mut decoder = Decoder::new(buf);
+        let history = (0..decoder.get_u32()?)
+            .map(|_| {
+                Ok(Self {
+                    size: decoder.get_u64()?,
+                    created_at: decoder.get_u64()?,
+                    expires_at: match decoder.get_u64()? {
+                        0 => None,
+                        expires_at => Some(expires_at),
+                    },
+                    once: decoder.get_u8()? != 0,
+                    e2e: decoder.get_u8()? != 0,
+                })
+            })
+            .collect::<Result<_, CodecError>>()?;
+        decoder.finish()?;
+
+        Ok(history)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let history = vec![
+            HistoryEntry {
+                size: 5,
+                created_at: 1_700_000_000,
+                expires_at: Some(1_700_003_600),
+                once: true,
+                e2e: false,
+            },
+            HistoryEntry {
+                size: 0,
+                created_at: 1_600_000_000,
+                expires_at: None,
+                once: false,
+                e2e: true,
+            },
+        ];
+
+        let buf = HistoryEntry::list_to_bytes(&history);
+        assert_eq!(HistoryEntry::list_from_bytes(&buf).unwrap(), history);
+        assert!(HistoryEntry::list_from_bytes(&buf[..buf.len() - 1]).is_err());
+    }
+}
@@ -1,8 +1,24 @@
+#[cfg(feature = "tokio")]
+mod async_frame;
+mod clip;
+mod codec;
 mod config;
+mod content;
+mod duration;
 mod frame;
+mod history;
 mod packet;
 mod slice;
+mod transfer;
 
+#[cfg(feature = "tokio")]
+pub use async_frame::*;
+pub use clip::*;
+pub use codec::*;
 pub use config::*;
+pub use content::*;
+pub use duration::*;
 pub use frame::*;
+pub use history::*;
 pub use packet::*;
+pub use transfer::*;
@@ -91,14 +91,14 @@ impl Packet {
         );
 
         slice!(buf[SECTION_TYPE_OFFSET; SECTION_TYPE_SIZE])
-            .copy_from_slice(&to_sized_section_type_size_byte_slice(&ty));
+            .copy_from_slice(&to_sized_section_type_size_byte_slice(ty));
 
         slice!(buf[SECTION_PAYLOAD_LEN_OFFSET; SECTION_PAYLOAD_LEN_SIZE]).copy_from_slice(
             &to_sized_section_payload_len_size_byte_slice(&payload.len().to_le_bytes()),
         );
 
         slice!(buf[SECTION_PAYLOAD_OFFSET; SECTION_PAYLOAD_SIZE])
-            .copy_from_slice(&to_sized_section_payload_size_byte_slice(&payload));
+            .copy_from_slice(&to_sized_section_payload_size_byte_slice(payload));
 
         packet
     }
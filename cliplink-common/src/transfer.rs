@@ -0,0 +1,182 @@
+use sha2::{Digest, Sha256};
+
+/// Size of each chunk frame sent while transferring a clip.
+pub const CHUNK_SIZE: usize = 256 * 1024; // 256 KiB
+
+/// SHA-256 digest size.
+pub const DIGEST_SIZE: usize = 32;
+
+/// Transfer header layout:
+/// * size: 8 bytes (big-endian)
+/// * digest: 32 bytes (SHA-256 of the whole clip)
+pub const TRANSFER_HEADER_LEN: usize = 8 + DIGEST_SIZE;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum TransferError {
+    #[error("invalid transfer header")]
+    InvalidHeader,
+
+    #[error("transfer too large: {size} bytes (max {max})")]
+    TooLarge { size: u64, max: u64 },
+
+    #[error("transfer overflow: more bytes than announced ({size})")]
+    Overflow { size: u64 },
+
+    #[error("transfer size mismatch: expected {expected} bytes, got {actual}")]
+    SizeMismatch { expected: u64, actual: u64 },
+
+    #[error("transfer digest mismatch")]
+    DigestMismatch,
+}
+
+/// Announces a chunked transfer: total size and digest of the clip.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferHeader {
+    pub size: u64,
+    pub digest: [u8; DIGEST_SIZE],
+}
+
+impl TransferHeader {
+    pub fn new(buf: &[u8]) -> Self {
+        Self {
+            size: buf.len() as u64,
+            digest: Sha256::digest(buf).into(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(TRANSFER_HEADER_LEN);
+        buf.extend_from_slice(&self.size.to_be_bytes());
+        buf.extend_from_slice(&self.digest);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, TransferError> {
+        if buf.len() != TRANSFER_HEADER_LEN {
+            return Err(TransferError::InvalidHeader);
+        }
+
+        let mut size = [0u8; 8];
+        size.copy_from_slice(&buf[..8]);
+
+        let mut digest = [0u8; DIGEST_SIZE];
+        digest.copy_from_slice(&buf[8..]);
+
+        Ok(Self {
+            size: u64::from_be_bytes(size),
+            digest,
+        })
+    }
+}
+
+/// Reassembles a clip from its chunks, enforcing the announced size and digest.
+#[derive(Debug)]
+pub struct Transfer {
+    header: TransferHeader,
+    buf: Vec<u8>,
+}
+
+impl Transfer {
+    pub fn new(header: TransferHeader, max_size: u64) -> Result<Self, TransferError> {
+        if header.size > max_size {
+            return Err(TransferError::TooLarge {
+                size: header.size,
+                max: max_size,
+            });
+        }
+
+        Ok(Self {
+            buf: Vec::with_capacity((header.size as usize).min(CHUNK_SIZE)),
+            header,
+        })
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Result<(), TransferError> {
+        if self.buf.len() as u64 + chunk.len() as u64 > self.header.size {
+            return Err(TransferError::Overflow {
+                size: self.header.size,
+            });
+        }
+
+        self.buf.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<Vec<u8>, TransferError> {
+        if self.buf.len() as u64 != self.header.size {
+            return Err(TransferError::SizeMismatch {
+                expected: self.header.size,
+                actual: self.buf.len() as u64,
+            });
+        }
+
+        if TransferHeader::new(&self.buf).digest != self.header.digest {
+            return Err(TransferError::DigestMismatch);
+        }
+
+        Ok(self.buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn header_roundtrip() {
+        let header = TransferHeader::new(b"public keypair");
+        let decoded = TransferHeader::from_bytes(&header.to_bytes()).unwrap();
+
+        assert_eq!(decoded, header);
+        assert_eq!(decoded.size, 14);
+        assert_eq!(
+            TransferHeader::from_bytes(&[0u8; 3]).unwrap_err(),
+            TransferError::InvalidHeader
+        );
+    }
+
+    #[test]
+    fn chunked_roundtrip() {
+        let clip = b"stack trace line\n".repeat(CHUNK_SIZE / 4);
+        let mut transfer = Transfer::new(TransferHeader::new(&clip), u64::MAX).unwrap();
+
+        for chunk in clip.chunks(CHUNK_SIZE) {
+            transfer.push(chunk).unwrap();
+        }
+
+        assert_eq!(transfer.finish().unwrap(), clip);
+    }
+
+    #[test]
+    fn rejects_invalid_transfers() {
+        let header = TransferHeader::new(b"public keypair");
+
+        assert_eq!(
+            Transfer::new(header.clone(), 4).unwrap_err(),
+            TransferError::TooLarge { size: 14, max: 4 }
+        );
+
+        let mut transfer = Transfer::new(header.clone(), u64::MAX).unwrap();
+        assert_eq!(
+            transfer.push(&[0u8; 15]).unwrap_err(),
+            TransferError::Overflow { size: 14 }
+        );
+
+        let mut transfer = Transfer::new(header.clone(), u64::MAX).unwrap();
+        transfer.push(b"public").unwrap();
+        assert_eq!(
+            transfer.finish().unwrap_err(),
+            TransferError::SizeMismatch {
+                expected: 14,
+                actual: 6
+            }
+        );
+
+        let mut transfer = Transfer::new(header, u64::MAX).unwrap();
+        transfer.push(b"public keypaiR").unwrap();
+        assert_eq!(
+            transfer.finish().unwrap_err(),
+            TransferError::DigestMismatch
+        );
+    }
+}
@@ -1,10 +1,9 @@
-use aes_gcm::{Aes256Gcm, AesGcm, KeyInit, Nonce, aead::Aead, aes};
-use rand::{RngCore, rngs::OsRng};
-use sha2::digest::{
-    consts::{B0, B1},
-    crypto_common,
-    typenum::{UInt, UTerm},
+use aes_gcm::{
+    Aes256Gcm, KeyInit, Nonce,
+    aead::{Aead, Payload},
 };
+use rand::{RngCore, rngs::OsRng};
+use sha2::digest::crypto_common;
 
 pub const AES_256_SIZE: usize = 32;
 pub const NONCE_SIZE: usize = 12;
@@ -28,10 +27,26 @@ impl From<aes_gcm::Error> for AesError {
     }
 }
 
-pub struct Aes256(
-    [u8; AES_256_SIZE],
-    AesGcm<aes::Aes256, UInt<UInt<UInt<UInt<UTerm, B1>, B1>, B0>, B0>>,
-);
+/// Traffic direction, mixed into sequence nonces so that the two directions never share a nonce,
+/// even under a shared key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Direction {
+    ClientToServer = 0,
+    ServerToClient = 1,
+}
+
+/// Nonce for message number `seq` in `direction`.
+///
+/// Layout: `direction (1) || zero (3) || seq (8, big-endian)`.
+pub fn sequence_nonce(direction: Direction, seq: u64) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[0] = direction as u8;
+    nonce[4..].copy_from_slice(&seq.to_be_bytes());
+    nonce
+}
+
+pub struct Aes256([u8; AES_256_SIZE], Aes256Gcm);
 
 impl TryFrom<[u8; AES_256_SIZE]> for Aes256 {
     type Error = AesError;
@@ -61,17 +76,52 @@ impl Aes256 {
         let mut nonce = [0u8; 12];
         OsRng.fill_bytes(&mut nonce);
 
-        let enc_buf = self.1.encrypt(Nonce::from_slice(&nonce), buf)?;
+        Ok((nonce, self.encrypt_with_nonce(nonce, buf)?))
+    }
+
+    /// Encrypts under a caller-chosen nonce, which must never repeat for this key.
+    pub fn encrypt_with_nonce(&self, nonce: [u8; 12], buf: &[u8]) -> Result<Vec<u8>, AesError> {
+        let enc_buf = self.1.encrypt(&Nonce::from(nonce), buf)?;
 
         if enc_buf.len() != buf.len() + GCM_AUTHENTICATION_TAG_SIZE {
             return Err(AesError::EncryptedOutputLength);
         }
 
-        Ok((nonce, enc_buf))
+        Ok(enc_buf)
     }
 
     pub fn decrypt(&self, nonce: [u8; 12], buf: &[u8]) -> Result<Vec<u8>, AesError> {
-        let dec_buf = self.1.decrypt(Nonce::from_slice(&nonce), buf)?;
+        let dec_buf = self.1.decrypt(&Nonce::from(nonce), buf)?;
+
+        Ok(dec_buf)
+    }
+
+    /// Like [`Aes256::encrypt`], also authenticating `aad`, which must be given unchanged to
+    /// [`Aes256::decrypt_with_aad`].
+    pub fn encrypt_with_aad(
+        &self,
+        buf: &[u8],
+        aad: &[u8],
+    ) -> Result<([u8; 12], Vec<u8>), AesError> {
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+
+        let enc_buf = self
+            .1
+            .encrypt(&Nonce::from(nonce), Payload { msg: buf, aad })?;
+
+        Ok((nonce, enc_buf))
+    }
+
+    pub fn decrypt_with_aad(
+        &self,
+        nonce: [u8; 12],
+        buf: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, AesError> {
+        let dec_buf = self
+            .1
+            .decrypt(&Nonce::from(nonce), Payload { msg: buf, aad })?;
 
         Ok(dec_buf)
     }
@@ -79,7 +129,7 @@ impl Aes256 {
 
 #[cfg(test)]
 mod test {
-    use crate::Aes256;
+    use crate::{Aes256, Direction, sequence_nonce};
 
     #[test]
     fn symmetric_encrypt_decrypt() {
@@ -92,4 +142,24 @@ mod test {
         assert_ne!(enc_buf, plain.as_bytes());
         assert_eq!(dec_buf, plain.as_bytes());
     }
+
+    #[test]
+    fn sequence_nonces() {
+        let aes_key = Aes256::new().unwrap();
+
+        let nonce = sequence_nonce(Direction::ClientToServer, 7);
+        let enc_buf = aes_key.encrypt_with_nonce(nonce, b"paste").unwrap();
+
+        assert_eq!(aes_key.decrypt(nonce, &enc_buf).unwrap(), b"paste");
+        assert!(
+            aes_key
+                .decrypt(sequence_nonce(Direction::ClientToServer, 6), &enc_buf)
+                .is_err()
+        );
+        assert!(
+            aes_key
+                .decrypt(sequence_nonce(Direction::ServerToClient, 7), &enc_buf)
+                .is_err()
+        );
+    }
 }
@@ -0,0 +1,386 @@
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+};
+
+use cliplink_common::{CodecError, Decoder, Encoder};
+
+use crate::PubKey;
+
+/// Environment variable holding the `ssh-agent` socket path.
+pub const AGENT_SOCK_ENV: &str = "SSH_AUTH_SOCK";
+
+/// Largest agent reply read, as in OpenSSH.
+const MAX_AGENT_MESSAGE: usize = 256 * 1024;
+
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+
+/// Sign flag asking for `rsa-sha2-256` signatures from RSA keys.
+const SSH_AGENT_RSA_SHA2_256: u32 = 2;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AgentError {
+    #[error("SSH_AUTH_SOCK is not set, is ssh-agent running?")]
+    NoAgent,
+
+    #[error("ssh-agent refused the request")]
+    Failure,
+
+    #[error("ssh-agent signed with {actual:?} instead of {expected:?}")]
+    WrongAlgorithm {
+        expected: &'static str,
+        actual: String,
+    },
+
+    #[error("invalid ssh-agent message: {0}")]
+    Message(#[from] CodecError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    SshKeyError(#[from] ssh_key::Error),
+}
+
+/// An identity held by `ssh-agent`.
+pub struct AgentKey {
+    blob: Vec<u8>,
+    pub_key: PubKey,
+    comment: String,
+}
+
+impl AgentKey {
+    pub fn pub_key(&self) -> &PubKey {
+        &self.pub_key
+    }
+
+    pub fn comment(&self) -> &str {
+        &self.comment
+    }
+}
+
+/// Client for the `ssh-agent` protocol, so that identities kept in the agent sign the handshake
+/// without their private key ever being loaded.
+pub struct Agent(UnixStream);
+
+impl Agent {
+    /// Connects to the agent listening on `SSH_AUTH_SOCK`.
+    pub fn connect() -> Result<Self, AgentError> {
+        let path = std::env::var_os(AGENT_SOCK_ENV).ok_or(AgentError::NoAgent)?;
+
+        Self::connect_to(Path::new(&path))
+    }
+
+    pub fn connect_to(path: &Path) -> Result<Self, AgentError> {
+        Ok(Self(UnixStream::connect(path)?))
+    }
+
+    /// Lists the agent identities, skipping key types cliplink does not support.
+    pub fn identities(&mut self) -> Result<Vec<AgentKey>, AgentError> {
+        let reply = self.request(SSH_AGENTC_REQUEST_IDENTITIES, &[])?;
+        let mut decoder = Decoder::new(&reply);
+
+        if decoder.get_u8()? != SSH_AGENT_IDENTITIES_ANSWER {
+            return Err(AgentError::Failure);
+        }
+
+        let mut keys = Vec::new();
+        for _ in 0..decoder.get_u32()? {
+            let blob = decoder.get_bytes()?.to_vec();
+            let comment = decoder.get_str()?.to_string();
+
+            let Ok(pub_key) = ssh_key::PublicKey::from_bytes(&blob) else {
+                continue;
+            };
+            let Ok(pub_key) = PubKey::from_openssh(pub_key.to_openssh()?.as_bytes()) else {
+                continue;
+            };
+
+            keys.push(AgentKey {
+                blob,
+                pub_key,
+                comment,
+            });
+        }
+        decoder.finish()?;
+
+        Ok(keys)
+    }
+
+    /// Has the agent sign `buf` with `key`, returning the signature as [`crate::PrivKey::sign`]
+    /// would.
+    pub fn sign(&mut self, key: &AgentKey, buf: &[u8]) -> Result<Vec<u8>, AgentError> {
+        let (algorithm, flags) = match key.pub_key {
+            PubKey::Rsa(_) => ("rsa-sha2-256", SSH_AGENT_RSA_SHA2_256),
+            PubKey::Ed25519(_) => ("ssh-ed25519", 0),
+        };
+
+        let request = Encoder::default()
+            .put_bytes(&key.blob)
+            .put_bytes(buf)
+            .put_u32(flags)
+            .finish();
+        let reply = self.request(SSH_AGENTC_SIGN_REQUEST, &request)?;
+        let mut decoder = Decoder::new(&reply);
+
+        if decoder.get_u8()? != SSH_AGENT_SIGN_RESPONSE {
+            return Err(AgentError::Failure);
+        }
+
+        let signature = decoder.get_bytes()?;
+        decoder.finish()?;
+
+        let mut decoder = Decoder::new(signature);
+        let actual = decoder.get_str()?;
+        if actual != algorithm {
+            return Err(AgentError::WrongAlgorithm {
+                expected: algorithm,
+                actual: actual.to_string(),
+            });
+        }
+
+        let signature = decoder.get_bytes()?.to_vec();
+        decoder.finish()?;
+
+        Ok(signature)
+    }
+
+    /// Sends one message and reads the reply, type byte included.
+    fn request(&mut self, ty: u8, payload: &[u8]) -> Result<Vec<u8>, AgentError> {
+        let mut message = Encoder::default()
+            .put_u32(payload.len() as u32 + 1)
+            .put_u8(ty)
+            .finish();
+        message.extend_from_slice(payload);
+        self.0.write_all(&message)?;
+
+        let mut len_buf = [0u8; 4];
+        self.0.read_exact(&mut len_buf)?;
+        let len = u32::from_be_bytes(len_buf) as usize;
+
+        if len == 0 || len > MAX_AGENT_MESSAGE {
+            return Err(AgentError::Failure);
+        }
+
+        let mut reply = vec![0u8; len];
+        self.0.read_exact(&mut reply)?;
+
+        match reply[0] {
+            SSH_AGENT_FAILURE => Err(AgentError::Failure),
+            _ => Ok(reply),
+        }
+    }
+}
+
+/// `ssh-agent` stand-in for tests, here and in the crates using [`Agent`].
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util {
+    use std::{
+        os::unix::net::UnixListener,
+        path::{Path, PathBuf},
+        thread::JoinHandle,
+    };
+
+    use super::*;
+    use crate::PrivKey;
+
+    /// Minimal agent serving `keys` on `agent.sock` in `dir`, for one connection.
+    ///
+    /// RSA keys sign as `rsa-sha2-256` when asked to with [`SSH_AGENT_RSA_SHA2_256`], unless
+    /// `legacy_rsa` is set, in which case they claim `ssh-rsa` as agents predating it do.
+    pub fn stand_in(keys: Vec<PrivKey>, legacy_rsa: bool, dir: &Path) -> (PathBuf, JoinHandle<()>) {
+        let path = dir.join("agent.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let blob = |key: &PrivKey| {
+                let openssh = key.pub_key().to_openssh(None).unwrap();
+                ssh_key::PublicKey::from_openssh(&openssh)
+                    .unwrap()
+                    .to_bytes()
+                    .unwrap()
+            };
+
+            loop {
+                let mut len_buf = [0u8; 4];
+                if stream.read_exact(&mut len_buf).is_err() {
+                    return;
+                }
+                let mut message = vec![0u8; u32::from_be_bytes(len_buf) as usize];
+                stream.read_exact(&mut message).unwrap();
+
+                let mut decoder = Decoder::new(&message);
+                let mut reply = Encoder::default();
+                match decoder.get_u8().unwrap() {
+                    SSH_AGENTC_REQUEST_IDENTITIES => {
+                        reply
+                            .put_u8(SSH_AGENT_IDENTITIES_ANSWER)
+                            .put_u32(keys.len() as u32);
+                        for (index, key) in keys.iter().enumerate() {
+                            reply.put_bytes(&blob(key)).put_str(&format!("key{index}"));
+                        }
+                    }
+                    SSH_AGENTC_SIGN_REQUEST => {
+                        let key_blob = decoder.get_bytes().unwrap();
+                        let buf = decoder.get_bytes().unwrap();
+                        let flags = decoder.get_u32().unwrap();
+
+                        match keys.iter().find(|key| blob(key) == key_blob) {
+                            Some(key) => {
+                                let algorithm = match key {
+                                    PrivKey::Ed25519(_) => "ssh-ed25519",
+                                    PrivKey::Rsa(_)
+                                        if flags & SSH_AGENT_RSA_SHA2_256 != 0 && !legacy_rsa =>
+                                    {
+                                        "rsa-sha2-256"
+                                    }
+                                    PrivKey::Rsa(_) => "ssh-rsa",
+                                };
+                                let signature = Encoder::default()
+                                    .put_str(algorithm)
+                                    .put_bytes(&key.sign(buf))
+                                    .finish();
+                                reply.put_u8(SSH_AGENT_SIGN_RESPONSE).put_bytes(&signature);
+                            }
+                            None => {
+                                reply.put_u8(SSH_AGENT_FAILURE);
+                            }
+                        }
+                    }
+                    _ => {
+                        reply.put_u8(SSH_AGENT_FAILURE);
+                    }
+                }
+
+                let reply = reply.finish();
+                stream
+                    .write_all(&(reply.len() as u32).to_be_bytes())
+                    .unwrap();
+                stream.write_all(&reply).unwrap();
+            }
+        });
+
+        (path, handle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::OnceLock;
+
+    use super::{test_util::stand_in, *};
+    use crate::{Ed25519PrivKey, PrivKey};
+
+    /// An RSA key, generated once as that is slow.
+    fn rsa_key() -> PrivKey {
+        static RSA_KEY: OnceLock<String> = OnceLock::new();
+
+        let openssh = RSA_KEY.get_or_init(|| {
+            let rsa_priv_key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+            let keypair = ssh_key::private::RsaKeypair::try_from(&rsa_priv_key).unwrap();
+            let ssh_priv_key =
+                ssh_key::PrivateKey::new(ssh_key::private::KeypairData::Rsa(keypair), "").unwrap();
+
+            ssh_priv_key
+                .to_openssh(ssh_key::LineEnding::LF)
+                .unwrap()
+                .to_string()
+        });
+
+        PrivKey::from_openssh(openssh.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn lists_and_signs() {
+        let keys = vec![
+            PrivKey::Ed25519(Ed25519PrivKey::generate()),
+            PrivKey::Ed25519(Ed25519PrivKey::generate()),
+            rsa_key(),
+        ];
+        let pub_keys: Vec<_> = keys
+            .iter()
+            .map(|key| key.pub_key().to_openssh(None).unwrap())
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let (path, handle) = stand_in(keys, false, dir.path());
+
+        let mut agent = Agent::connect_to(&path).unwrap();
+        let identities = agent.identities().unwrap();
+        assert_eq!(identities.len(), 3);
+        assert_eq!(identities[1].comment(), "key1");
+        assert_eq!(
+            identities[1].pub_key().to_openssh(None).unwrap(),
+            pub_keys[1]
+        );
+
+        let signature = agent.sign(&identities[1], b"challenge").unwrap();
+        identities[1]
+            .pub_key()
+            .verify(b"challenge", &signature)
+            .unwrap();
+        assert!(
+            identities[0]
+                .pub_key()
+                .verify(b"challenge", &signature)
+                .is_err()
+        );
+
+        // Without the rsa-sha2-256 flag, the stand-in would answer with ssh-rsa.
+        assert!(matches!(identities[2].pub_key(), PubKey::Rsa(_)));
+        let signature = agent.sign(&identities[2], b"challenge").unwrap();
+        identities[2]
+            .pub_key()
+            .verify(b"challenge", &signature)
+            .unwrap();
+
+        drop(agent);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn rejects_other_signature_algorithm() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, handle) = stand_in(vec![rsa_key()], true, dir.path());
+
+        let mut agent = Agent::connect_to(&path).unwrap();
+        let identities = agent.identities().unwrap();
+        assert!(matches!(
+            agent.sign(&identities[0], b"challenge"),
+            Err(AgentError::WrongAlgorithm {
+                expected: "rsa-sha2-256",
+                actual,
+            }) if actual == "ssh-rsa"
+        ));
+
+        drop(agent);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn surfaces_agent_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, handle) = stand_in(Vec::new(), false, dir.path());
+
+        let mut agent = Agent::connect_to(&path).unwrap();
+        assert!(agent.identities().unwrap().is_empty());
+
+        let unknown = AgentKey {
+            blob: b"unknown".to_vec(),
+            pub_key: PrivKey::Ed25519(Ed25519PrivKey::generate()).pub_key(),
+            comment: String::new(),
+        };
+        assert!(matches!(
+            agent.sign(&unknown, b"challenge"),
+            Err(AgentError::Failure)
+        ));
+
+        drop(agent);
+        handle.join().unwrap();
+    }
+}
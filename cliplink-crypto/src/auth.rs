@@ -0,0 +1,44 @@
+use rand::{RngCore, rngs::OsRng};
+use sha2::{Digest, Sha256};
+
+/// Size of the random challenge the server sends in `sshsynack`.
+pub const CHALLENGE_SIZE: usize = 32;
+
+const AUTH_LABEL: &[u8] = b"cliplink-auth-v1";
+const HOST_LABEL: &[u8] = b"cliplink-host-v1";
+
+pub fn challenge() -> [u8; CHALLENGE_SIZE] {
+    let mut challenge = [0u8; CHALLENGE_SIZE];
+    OsRng.fill_bytes(&mut challenge);
+    challenge
+}
+
+/// Running hash of the handshake messages.
+///
+/// Both sides feed it the `sshsyn` and `sshsynack` payloads. The client signs
+/// [`Transcript::auth_message`] with its identity key, proving possession of the private key for
+/// this very handshake: the server challenge, the client key and both ephemeral keys included.
+/// The server likewise signs [`Transcript::host_message`] with its host key.
+#[derive(Clone, Default)]
+pub struct Transcript(Sha256);
+
+impl Transcript {
+    pub fn update(&mut self, buf: &[u8]) {
+        self.0.update((buf.len() as u64).to_be_bytes());
+        self.0.update(buf);
+    }
+
+    pub fn auth_message(&self) -> Vec<u8> {
+        self.message(AUTH_LABEL)
+    }
+
+    pub fn host_message(&self) -> Vec<u8> {
+        self.message(HOST_LABEL)
+    }
+
+    fn message(&self, label: &[u8]) -> Vec<u8> {
+        let mut message = label.to_vec();
+        message.extend_from_slice(&self.0.clone().finalize());
+        message
+    }
+}
@@ -0,0 +1,138 @@
+use cliplink_common::Encoder;
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::{AES_256_SIZE, Aes256, AesError, NONCE_SIZE};
+
+/// Message an identity signs to derive its clip key.
+///
+/// Ed25519 and `rsa-sha2-256` signatures are deterministic, so every device holding the identity,
+/// as a key file or in `ssh-agent`, derives the same clip key. Handshake signatures always cover a
+/// `cliplink-auth-v1` message, so a server can never obtain this one.
+///
+/// Anyone able to use the identity can sign it though, so whoever can reach a forwarded
+/// `ssh-agent`, such as root on the remote host, can derive the clip key and read every clip.
+pub const CLIP_KEY_MESSAGE: &[u8] = b"cliplink-e2e-v1 clip key";
+
+const CLIP_KEY_INFO: &[u8] = b"cliplink-e2e-v1 aes-256-gcm";
+
+#[derive(Debug, thiserror::Error)]
+pub enum E2eError {
+    #[error(
+        "failed to decrypt clip, it was encrypted by another identity, for another clip or content \
+         type, or altered"
+    )]
+    Decryption,
+
+    #[error(transparent)]
+    Aes(#[from] AesError),
+}
+
+/// Key for end-to-end encrypted clips, which the server stores but cannot read.
+pub struct ClipKey(Aes256);
+
+impl ClipKey {
+    /// Derives the clip key from an identity signature over [`CLIP_KEY_MESSAGE`].
+    pub fn derive(signature: &[u8]) -> Result<Self, E2eError> {
+        let hkdf = Hkdf::<Sha256>::new(None, signature);
+
+        let mut key = [0u8; AES_256_SIZE];
+        hkdf.expand(CLIP_KEY_INFO, &mut key)
+            .expect("32 bytes is a valid hkdf output length");
+
+        Ok(Self(Aes256::try_from(key)?))
+    }
+
+    /// Encrypts a clip payload, bound to the name of the clip it is pasted to and its content
+    /// type, so that the server can't pass it off as another clip or type.
+    ///
+    /// Layout: `nonce (12) || ciphertext`.
+    pub fn seal(&self, buf: &[u8], clip: &str, content_type: &str) -> Result<Vec<u8>, E2eError> {
+        let aad = associated_data(clip, content_type);
+        let (nonce, enc_buf) = self.0.encrypt_with_aad(buf, &aad)?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&enc_buf);
+        Ok(sealed)
+    }
+
+    /// Decrypts a payload produced by [`ClipKey::seal`] with the same `clip` and `content_type`.
+    pub fn open(&self, buf: &[u8], clip: &str, content_type: &str) -> Result<Vec<u8>, E2eError> {
+        if buf.len() < NONCE_SIZE {
+            return Err(E2eError::Decryption);
+        }
+
+        let (nonce, enc_buf) = buf.split_at(NONCE_SIZE);
+        let nonce = nonce.try_into().map_err(|_| E2eError::Decryption)?;
+
+        self.0
+            .decrypt_with_aad(nonce, enc_buf, &associated_data(clip, content_type))
+            .map_err(|_| E2eError::Decryption)
+    }
+}
+
+/// AES-GCM associated data: the length-prefixed clip name and content type.
+fn associated_data(clip: &str, content_type: &str) -> Vec<u8> {
+    Encoder::default()
+        .put_str(clip)
+        .put_str(content_type)
+        .finish()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{CLIP_KEY_MESSAGE, ClipKey, E2eError, Ed25519PrivKey, PrivKey};
+
+    #[test]
+    fn same_identity_reads_clip() {
+        let priv_key = PrivKey::Ed25519(Ed25519PrivKey::generate());
+
+        // two devices sharing the identity
+        let key = ClipKey::derive(&priv_key.sign(CLIP_KEY_MESSAGE)).unwrap();
+        let other_device = ClipKey::derive(&priv_key.sign(CLIP_KEY_MESSAGE)).unwrap();
+
+        let sealed = key.seal(b"secret clip", "notes", "text/plain").unwrap();
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+        assert_eq!(
+            other_device.open(&sealed, "notes", "text/plain").unwrap(),
+            b"secret clip"
+        );
+    }
+
+    #[test]
+    fn other_identity_cannot_read_clip() {
+        let key =
+            ClipKey::derive(&PrivKey::Ed25519(Ed25519PrivKey::generate()).sign(CLIP_KEY_MESSAGE))
+                .unwrap();
+        let other =
+            ClipKey::derive(&PrivKey::Ed25519(Ed25519PrivKey::generate()).sign(CLIP_KEY_MESSAGE))
+                .unwrap();
+
+        let sealed = key.seal(b"secret clip", "notes", "text/plain").unwrap();
+        assert!(matches!(
+            other.open(&sealed, "notes", "text/plain"),
+            Err(E2eError::Decryption)
+        ));
+        assert!(matches!(
+            key.open(&sealed[..8], "notes", "text/plain"),
+            Err(E2eError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn clip_is_bound_to_name_and_content_type() {
+        let key =
+            ClipKey::derive(&PrivKey::Ed25519(Ed25519PrivKey::generate()).sign(CLIP_KEY_MESSAGE))
+                .unwrap();
+
+        let sealed = key.seal(b"secret clip", "notes", "text/plain").unwrap();
+        assert!(matches!(
+            key.open(&sealed, "default", "text/plain"),
+            Err(E2eError::Decryption)
+        ));
+        assert!(matches!(
+            key.open(&sealed, "notes", "text/html"),
+            Err(E2eError::Decryption)
+        ));
+    }
+}
@@ -0,0 +1,214 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use rand::{RngCore, rngs::OsRng};
+use sha2::Sha256;
+use ssh_key::{PrivateKey, private::KeypairData, public::KeyData};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::{AES_256_SIZE, Aes256, NONCE_SIZE, RsaError};
+
+pub const X25519_SIZE: usize = 32;
+
+const SEAL_INFO: &[u8] = b"cliplink-ed25519-seal-v1";
+
+/// Derives the sealing key from an X25519 shared secret, bound to both public halves.
+fn seal_key(
+    shared: &[u8; X25519_SIZE],
+    eph_pub_key: &[u8; X25519_SIZE],
+    recipient_pub_key: &[u8; X25519_SIZE],
+) -> Result<Aes256, RsaError> {
+    let mut salt = [0u8; 2 * X25519_SIZE];
+    salt[..X25519_SIZE].copy_from_slice(eph_pub_key);
+    salt[X25519_SIZE..].copy_from_slice(recipient_pub_key);
+
+    let mut okm = [0u8; AES_256_SIZE];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(SEAL_INFO, &mut okm)
+        .expect("32 bytes is a valid hkdf output length");
+
+    Ok(Aes256::try_from(okm)?)
+}
+
+pub struct Ed25519PubKey(VerifyingKey);
+
+impl Ed25519PubKey {
+    pub fn from_openssh(pub_key: &[u8]) -> Result<Self, RsaError> {
+        let pub_key = ssh_key::public::PublicKey::from_openssh(str::from_utf8(pub_key)?)?;
+
+        let ed25519 = match pub_key.key_data() {
+            KeyData::Ed25519(ed25519) => ed25519,
+            _ => return Err(RsaError::KeyNotSupported),
+        };
+
+        Ok(Self(VerifyingKey::try_from(ed25519)?))
+    }
+
+    pub fn to_openssh(&self, comment: Option<String>) -> Result<String, RsaError> {
+        let pub_key = ssh_key::PublicKey::new(
+            KeyData::Ed25519(ssh_key::public::Ed25519PublicKey::from(self.0)),
+            comment.unwrap_or_default(),
+        );
+
+        Ok(pub_key.to_openssh()?)
+    }
+
+    /// Seals `buf` to this key: an ephemeral X25519 exchange against the key's Montgomery form,
+    /// with the AES key derived through HKDF-SHA256.
+    ///
+    /// Output layout: `ephemeral public key (32) || nonce (12) || ciphertext`.
+    pub fn seal(&self, buf: &[u8]) -> Result<Vec<u8>, RsaError> {
+        let recipient_pub_key = self.0.to_montgomery().to_bytes();
+
+        let eph_secret = EphemeralSecret::random_from_rng(OsRng);
+        let eph_pub_key = PublicKey::from(&eph_secret).to_bytes();
+        let shared = eph_secret.diffie_hellman(&PublicKey::from(recipient_pub_key));
+
+        if !shared.was_contributory() {
+            return Err(RsaError::KeyAgreement);
+        }
+
+        let aes_key = seal_key(shared.as_bytes(), &eph_pub_key, &recipient_pub_key)?;
+        let (nonce, enc_buf) = aes_key.encrypt(buf)?;
+
+        let mut sealed = Vec::with_capacity(X25519_SIZE + NONCE_SIZE + enc_buf.len());
+        sealed.extend_from_slice(&eph_pub_key);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&enc_buf);
+
+        Ok(sealed)
+    }
+
+    pub fn verify(&self, buf: &[u8], signature: &[u8]) -> Result<(), RsaError> {
+        let signature =
+            Signature::from_slice(signature).map_err(|_| RsaError::SignatureVerification)?;
+
+        self.0
+            .verify(buf, &signature)
+            .map_err(|_| RsaError::SignatureVerification)
+    }
+}
+
+pub struct Ed25519PrivKey(SigningKey);
+
+impl Ed25519PrivKey {
+    pub fn generate() -> Self {
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+
+        Self(SigningKey::from_bytes(&seed))
+    }
+
+    pub fn from_openssh(priv_key: &[u8]) -> Result<Self, RsaError> {
+        Self::from_ssh_key(&PrivateKey::from_openssh(priv_key)?)
+    }
+
+    /// Converts a decrypted `ssh-key` private key.
+    pub(crate) fn from_ssh_key(priv_key: &PrivateKey) -> Result<Self, RsaError> {
+        let ed25519 = match priv_key.key_data() {
+            KeypairData::Ed25519(key) => SigningKey::try_from(key)?,
+            _ => return Err(RsaError::KeyNotSupported),
+        };
+
+        Ok(Self(ed25519))
+    }
+
+    pub fn to_openssh(&self) -> Result<String, RsaError> {
+        let priv_key = ssh_key::PrivateKey::new(
+            KeypairData::Ed25519(ssh_key::private::Ed25519Keypair::from(&self.0)),
+            "",
+        )?;
+
+        Ok(priv_key.to_openssh(ssh_key::LineEnding::LF)?.to_string())
+    }
+
+    /// Opens a buffer produced by [`Ed25519PubKey::seal`].
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, RsaError> {
+        if sealed.len() < X25519_SIZE + NONCE_SIZE {
+            return Err(RsaError::KeyAgreement);
+        }
+
+        let mut eph_pub_key = [0u8; X25519_SIZE];
+        eph_pub_key.copy_from_slice(&sealed[..X25519_SIZE]);
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(&sealed[X25519_SIZE..X25519_SIZE + NONCE_SIZE]);
+
+        let recipient_pub_key = self.0.verifying_key().to_montgomery().to_bytes();
+        let shared = x25519_dalek::x25519(self.0.to_scalar_bytes(), eph_pub_key);
+
+        if shared == [0u8; X25519_SIZE] {
+            return Err(RsaError::KeyAgreement);
+        }
+
+        let aes_key = seal_key(&shared, &eph_pub_key, &recipient_pub_key)?;
+
+        Ok(aes_key.decrypt(nonce, &sealed[X25519_SIZE + NONCE_SIZE..])?)
+    }
+
+    pub fn sign(&self, buf: &[u8]) -> Vec<u8> {
+        self.0.sign(buf).to_vec()
+    }
+
+    pub fn pub_key(&self) -> Ed25519PubKey {
+        Ed25519PubKey(self.0.verifying_key())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Ed25519PrivKey, Ed25519PubKey};
+
+    fn ed25519_keypair() -> (String, String) {
+        let priv_key = Ed25519PrivKey::generate();
+
+        (
+            priv_key.to_openssh().unwrap(),
+            priv_key.pub_key().to_openssh(None).unwrap(),
+        )
+    }
+
+    #[test]
+    fn seal_open() {
+        let (priv_key_openssh, pub_key_openssh) = ed25519_keypair();
+
+        let pub_key = Ed25519PubKey::from_openssh(pub_key_openssh.as_bytes()).unwrap();
+        let priv_key = Ed25519PrivKey::from_openssh(priv_key_openssh.as_bytes()).unwrap();
+
+        let plain = "my plain text";
+        let sealed = pub_key.seal(plain.as_bytes()).unwrap();
+        let opened = priv_key.open(&sealed).unwrap();
+
+        assert_ne!(sealed, plain.as_bytes());
+        assert_eq!(opened, plain.as_bytes());
+        assert_eq!(
+            priv_key.pub_key().to_openssh(None).unwrap(),
+            pub_key.to_openssh(None).unwrap()
+        );
+    }
+
+    #[test]
+    fn open_with_wrong_key() {
+        let (_, pub_key_openssh) = ed25519_keypair();
+        let (priv_key_openssh, _) = ed25519_keypair();
+
+        let pub_key = Ed25519PubKey::from_openssh(pub_key_openssh.as_bytes()).unwrap();
+        let priv_key = Ed25519PrivKey::from_openssh(priv_key_openssh.as_bytes()).unwrap();
+
+        let sealed = pub_key.seal(b"my plain text").unwrap();
+
+        assert!(priv_key.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn sign_verify() {
+        let (priv_key_openssh, pub_key_openssh) = ed25519_keypair();
+
+        let pub_key = Ed25519PubKey::from_openssh(pub_key_openssh.as_bytes()).unwrap();
+        let priv_key = Ed25519PrivKey::from_openssh(priv_key_openssh.as_bytes()).unwrap();
+
+        let signature = priv_key.sign(b"challenge");
+
+        pub_key.verify(b"challenge", &signature).unwrap();
+        assert!(pub_key.verify(b"challengf", &signature).is_err());
+    }
+}
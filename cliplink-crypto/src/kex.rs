@@ -0,0 +1,381 @@
+use hkdf::Hkdf;
+use rand::{RngCore, rngs::OsRng};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::{AES_256_SIZE, Aes256, PrivKey, PubKey, RsaError, RsaPadding, X25519_SIZE};
+
+const KEX_TRANSCRIPT_LABEL: &[u8] = b"cliplink-kex-v1";
+const KEX_CLIENT_INFO: &[u8] = b"cliplink-kex-v1 client to server";
+const KEX_SERVER_INFO: &[u8] = b"cliplink-kex-v1 server to client";
+
+/// Key exchange mode, negotiated by the client in `sshsyn`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KexMode {
+    /// The server seals a random AES key to the client identity. Anyone who later obtains the
+    /// identity private key can recover the session key. Only available with the
+    /// `legacy-sealed-kex` feature, for clients that predate the ephemeral modes.
+    #[cfg(feature = "legacy-sealed-kex")]
+    Sealed = 0,
+
+    /// Both sides contribute an ephemeral X25519 key. The server also seals a random secret to the
+    /// client identity, so only its holder can derive the session keys, and both are fed to
+    /// HKDF-SHA256 over the handshake transcript. Ephemeral secrets never outlive the handshake.
+    Ephemeral = 1,
+
+    /// Both sides contribute an ephemeral X25519 key and nothing is sealed to the client identity:
+    /// the session keys come from the key agreement alone, and the client proves its identity
+    /// only by signing the transcript in `sshauth`. Used with `ssh-agent` identities, which can
+    /// sign but not decrypt.
+    Signed = 2,
+}
+
+impl TryFrom<u8> for KexMode {
+    type Error = RsaError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            #[cfg(feature = "legacy-sealed-kex")]
+            0 => Ok(Self::Sealed),
+            1 => Ok(Self::Ephemeral),
+            2 => Ok(Self::Signed),
+            mode => Err(RsaError::KexModeNotSupported(mode)),
+        }
+    }
+}
+
+/// Per-direction session keys.
+pub struct SessionKeys {
+    /// Encrypts client to server traffic.
+    pub client: Aes256,
+
+    /// Encrypts server to client traffic.
+    pub server: Aes256,
+}
+
+impl SessionKeys {
+    #[cfg(feature = "legacy-sealed-kex")]
+    fn shared(key: [u8; AES_256_SIZE]) -> Result<Self, RsaError> {
+        Ok(Self {
+            client: Aes256::try_from(key)?,
+            server: Aes256::try_from(key)?,
+        })
+    }
+
+    fn derive(
+        dh: &[u8; X25519_SIZE],
+        secret: &[u8],
+        transcript: &[u8; 32],
+    ) -> Result<Self, RsaError> {
+        let mut ikm = Vec::with_capacity(dh.len() + secret.len());
+        ikm.extend_from_slice(dh);
+        ikm.extend_from_slice(secret);
+
+        let hkdf = Hkdf::<Sha256>::new(Some(transcript), &ikm);
+
+        let mut client = [0u8; AES_256_SIZE];
+        let mut server = [0u8; AES_256_SIZE];
+        hkdf.expand(KEX_CLIENT_INFO, &mut client)
+            .expect("32 bytes is a valid hkdf output length");
+        hkdf.expand(KEX_SERVER_INFO, &mut server)
+            .expect("32 bytes is a valid hkdf output length");
+
+        Ok(Self {
+            client: Aes256::try_from(client)?,
+            server: Aes256::try_from(server)?,
+        })
+    }
+}
+
+fn transcript(
+    pub_key: &PubKey,
+    client_eph_pub_key: &[u8],
+    server_eph_pub_key: &[u8],
+) -> Result<[u8; 32], RsaError> {
+    Ok(Sha256::new()
+        .chain_update(KEX_TRANSCRIPT_LABEL)
+        .chain_update(pub_key.to_openssh(None)?.as_bytes())
+        .chain_update(client_eph_pub_key)
+        .chain_update(server_eph_pub_key)
+        .finalize()
+        .into())
+}
+
+fn parse_eph_pub_key(buf: &[u8]) -> Result<PublicKey, RsaError> {
+    let buf: [u8; X25519_SIZE] = buf.try_into().map_err(|_| RsaError::KeyAgreement)?;
+    Ok(PublicKey::from(buf))
+}
+
+/// Client half of the key exchange.
+pub struct ClientKex {
+    mode: KexMode,
+    padding: RsaPadding,
+    eph_secret: Option<EphemeralSecret>,
+    eph_pub_key: Vec<u8>,
+}
+
+impl ClientKex {
+    pub fn new(mode: KexMode, padding: RsaPadding) -> Self {
+        match mode {
+            #[cfg(feature = "legacy-sealed-kex")]
+            KexMode::Sealed => Self {
+                mode,
+                padding,
+                eph_secret: None,
+                eph_pub_key: Vec::new(),
+            },
+            KexMode::Ephemeral | KexMode::Signed => {
+                let eph_secret = EphemeralSecret::random_from_rng(OsRng);
+                let eph_pub_key = PublicKey::from(&eph_secret).to_bytes().to_vec();
+
+                Self {
+                    mode,
+                    padding,
+                    eph_secret: Some(eph_secret),
+                    eph_pub_key,
+                }
+            }
+        }
+    }
+
+    pub fn mode(&self) -> KexMode {
+        self.mode
+    }
+
+    /// Padding the server must use when sealing to an RSA identity.
+    pub fn padding(&self) -> RsaPadding {
+        self.padding
+    }
+
+    /// Ephemeral public key to send in `sshsyn`, empty in `KexMode::Sealed`.
+    pub fn eph_pub_key(&self) -> &[u8] {
+        &self.eph_pub_key
+    }
+
+    /// Derives the session keys from the server reply to `sshsyn`.
+    pub fn finish(self, priv_key: &PrivKey, reply: &[u8]) -> Result<SessionKeys, RsaError> {
+        match (self.mode, self.eph_secret) {
+            #[cfg(feature = "legacy-sealed-kex")]
+            (KexMode::Sealed, _) => {
+                let key = priv_key
+                    .open(reply, self.padding)?
+                    .try_into()
+                    .map_err(|_| RsaError::KeyAgreement)?;
+
+                SessionKeys::shared(key)
+            }
+            (KexMode::Ephemeral, Some(eph_secret)) => {
+                if reply.len() < X25519_SIZE {
+                    return Err(RsaError::KeyAgreement);
+                }
+
+                let (server_eph_pub_key, sealed_secret) = reply.split_at(X25519_SIZE);
+
+                let dh = eph_secret.diffie_hellman(&parse_eph_pub_key(server_eph_pub_key)?);
+                if !dh.was_contributory() {
+                    return Err(RsaError::KeyAgreement);
+                }
+
+                let secret = priv_key.open(sealed_secret, self.padding)?;
+                let transcript =
+                    transcript(&priv_key.pub_key(), &self.eph_pub_key, server_eph_pub_key)?;
+
+                SessionKeys::derive(dh.as_bytes(), &secret, &transcript)
+            }
+            (KexMode::Signed, Some(eph_secret)) => {
+                signed_keys(eph_secret, &self.eph_pub_key, &priv_key.pub_key(), reply)
+            }
+            (KexMode::Ephemeral | KexMode::Signed, None) => Err(RsaError::KeyAgreement),
+        }
+    }
+
+    /// Derives the session keys in [`KexMode::Signed`], which takes no private key operation.
+    pub fn finish_signed(self, pub_key: &PubKey, reply: &[u8]) -> Result<SessionKeys, RsaError> {
+        match (self.mode, self.eph_secret) {
+            (KexMode::Signed, Some(eph_secret)) => {
+                signed_keys(eph_secret, &self.eph_pub_key, pub_key, reply)
+            }
+            _ => Err(RsaError::KeyAgreement),
+        }
+    }
+}
+
+fn signed_keys(
+    eph_secret: EphemeralSecret,
+    eph_pub_key: &[u8],
+    pub_key: &PubKey,
+    server_eph_pub_key: &[u8],
+) -> Result<SessionKeys, RsaError> {
+    let dh = eph_secret.diffie_hellman(&parse_eph_pub_key(server_eph_pub_key)?);
+    if !dh.was_contributory() {
+        return Err(RsaError::KeyAgreement);
+    }
+
+    let transcript = transcript(pub_key, eph_pub_key, server_eph_pub_key)?;
+
+    SessionKeys::derive(dh.as_bytes(), &[], &transcript)
+}
+
+/// Server half of the key exchange.
+pub struct ServerKex;
+
+impl ServerKex {
+    /// Answers a client `sshsyn`, returning the reply payload and the derived session keys.
+    pub fn respond(
+        mode: KexMode,
+        padding: RsaPadding,
+        pub_key: &PubKey,
+        client_eph_pub_key: &[u8],
+    ) -> Result<(Vec<u8>, SessionKeys), RsaError> {
+        match mode {
+            #[cfg(feature = "legacy-sealed-kex")]
+            KexMode::Sealed => {
+                let aes_key = Aes256::new()?;
+                let reply = pub_key.seal(aes_key.as_bytes(), padding)?;
+
+                Ok((reply, SessionKeys::shared(*aes_key.as_bytes())?))
+            }
+            KexMode::Ephemeral => {
+                let eph_secret = EphemeralSecret::random_from_rng(OsRng);
+                let eph_pub_key = PublicKey::from(&eph_secret).to_bytes();
+
+                let dh = eph_secret.diffie_hellman(&parse_eph_pub_key(client_eph_pub_key)?);
+                if !dh.was_contributory() {
+                    return Err(RsaError::KeyAgreement);
+                }
+
+                let mut secret = [0u8; 32];
+                OsRng.fill_bytes(&mut secret);
+
+                let mut reply = eph_pub_key.to_vec();
+                reply.extend_from_slice(&pub_key.seal(&secret, padding)?);
+
+                let transcript = transcript(pub_key, client_eph_pub_key, &eph_pub_key)?;
+
+                Ok((
+                    reply,
+                    SessionKeys::derive(dh.as_bytes(), &secret, &transcript)?,
+                ))
+            }
+            KexMode::Signed => {
+                let eph_secret = EphemeralSecret::random_from_rng(OsRng);
+                let eph_pub_key = PublicKey::from(&eph_secret).to_bytes();
+
+                let dh = eph_secret.diffie_hellman(&parse_eph_pub_key(client_eph_pub_key)?);
+                if !dh.was_contributory() {
+                    return Err(RsaError::KeyAgreement);
+                }
+
+                let transcript = transcript(pub_key, client_eph_pub_key, &eph_pub_key)?;
+
+                Ok((
+                    eph_pub_key.to_vec(),
+                    SessionKeys::derive(dh.as_bytes(), &[], &transcript)?,
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{ClientKex, Ed25519PrivKey, KexMode, PrivKey, RsaPadding, ServerKex};
+
+    fn ed25519_priv_key() -> PrivKey {
+        PrivKey::Ed25519(Ed25519PrivKey::generate())
+    }
+
+    fn exchange(mode: KexMode, priv_key: &PrivKey) {
+        let client = ClientKex::new(mode, RsaPadding::Oaep);
+        let (reply, server_keys) = ServerKex::respond(
+            mode,
+            client.padding(),
+            &priv_key.pub_key(),
+            client.eph_pub_key(),
+        )
+        .unwrap();
+        let client_keys = client.finish(priv_key, &reply).unwrap();
+
+        let (nonce, enc_buf) = client_keys.client.encrypt(b"paste").unwrap();
+        assert_eq!(
+            server_keys.client.decrypt(nonce, &enc_buf).unwrap(),
+            b"paste"
+        );
+
+        let (nonce, enc_buf) = server_keys.server.encrypt(b"pasteack").unwrap();
+        assert_eq!(
+            client_keys.server.decrypt(nonce, &enc_buf).unwrap(),
+            b"pasteack"
+        );
+    }
+
+    #[cfg(feature = "legacy-sealed-kex")]
+    #[test]
+    fn sealed_exchange() {
+        exchange(KexMode::Sealed, &ed25519_priv_key());
+    }
+
+    #[cfg(not(feature = "legacy-sealed-kex"))]
+    #[test]
+    fn sealed_not_supported() {
+        assert!(matches!(
+            KexMode::try_from(0),
+            Err(crate::RsaError::KexModeNotSupported(0))
+        ));
+    }
+
+    #[test]
+    fn ephemeral_exchange() {
+        let priv_key = ed25519_priv_key();
+        exchange(KexMode::Ephemeral, &priv_key);
+
+        // directions use distinct keys
+        let client = ClientKex::new(KexMode::Ephemeral, RsaPadding::Oaep);
+        let (reply, server_keys) = ServerKex::respond(
+            KexMode::Ephemeral,
+            RsaPadding::Oaep,
+            &priv_key.pub_key(),
+            client.eph_pub_key(),
+        )
+        .unwrap();
+        let client_keys = client.finish(&priv_key, &reply).unwrap();
+
+        assert_ne!(client_keys.client.as_bytes(), client_keys.server.as_bytes());
+        assert_eq!(client_keys.server.as_bytes(), server_keys.server.as_bytes());
+    }
+
+    #[test]
+    fn signed_exchange() {
+        let priv_key = ed25519_priv_key();
+        exchange(KexMode::Signed, &priv_key);
+
+        // no private key needed on the client side
+        let client = ClientKex::new(KexMode::Signed, RsaPadding::Oaep);
+        let (reply, server_keys) = ServerKex::respond(
+            KexMode::Signed,
+            RsaPadding::Oaep,
+            &priv_key.pub_key(),
+            client.eph_pub_key(),
+        )
+        .unwrap();
+        let client_keys = client.finish_signed(&priv_key.pub_key(), &reply).unwrap();
+
+        assert_eq!(client_keys.client.as_bytes(), server_keys.client.as_bytes());
+        assert_ne!(client_keys.client.as_bytes(), client_keys.server.as_bytes());
+    }
+
+    #[test]
+    fn ephemeral_exchange_wrong_identity() {
+        let client = ClientKex::new(KexMode::Ephemeral, RsaPadding::Oaep);
+        let (reply, _) = ServerKex::respond(
+            KexMode::Ephemeral,
+            RsaPadding::Oaep,
+            &ed25519_priv_key().pub_key(),
+            client.eph_pub_key(),
+        )
+        .unwrap();
+
+        assert!(client.finish(&ed25519_priv_key(), &reply).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use std::path::{Path, PathBuf};
+
+use ssh_key::{Algorithm, HashAlg};
+use zeroize::Zeroizing;
+
+use crate::{Ed25519PrivKey, Ed25519PubKey, RsaError, RsaPadding, RsaPrivKey, RsaPubKey};
+
+/// Public half of an SSH identity, either RSA or Ed25519.
+pub enum PubKey {
+    Rsa(RsaPubKey),
+    Ed25519(Ed25519PubKey),
+}
+
+impl PubKey {
+    pub fn from_openssh(pub_key: &[u8]) -> Result<Self, RsaError> {
+        let algorithm =
+            ssh_key::public::PublicKey::from_openssh(str::from_utf8(pub_key)?)?.algorithm();
+
+        match algorithm {
+            Algorithm::Rsa { .. } => Ok(Self::Rsa(RsaPubKey::from_openssh(pub_key)?)),
+            Algorithm::Ed25519 => Ok(Self::Ed25519(Ed25519PubKey::from_openssh(pub_key)?)),
+            _ => Err(RsaError::KeyNotSupported),
+        }
+    }
+
+    pub fn to_openssh(&self, comment: Option<String>) -> Result<String, RsaError> {
+        match self {
+            Self::Rsa(key) => key.to_openssh(comment),
+            Self::Ed25519(key) => key.to_openssh(comment),
+        }
+    }
+
+    /// SHA-256 fingerprint, as printed by `ssh-keygen -l`.
+    pub fn fingerprint(&self) -> Result<String, RsaError> {
+        let pub_key = ssh_key::PublicKey::from_openssh(&self.to_openssh(None)?)?;
+
+        Ok(pub_key.fingerprint(HashAlg::Sha256).to_string())
+    }
+
+    /// Encrypts `buf` so that only the holder of the matching private key can read it:
+    /// RSA encryption with `padding` for RSA keys, an X25519 key agreement for Ed25519 keys.
+    pub fn seal(&self, buf: &[u8], padding: RsaPadding) -> Result<Vec<u8>, RsaError> {
+        match self {
+            Self::Rsa(key) => key.encrypt(buf, padding),
+            Self::Ed25519(key) => key.seal(buf),
+        }
+    }
+
+    pub fn verify(&self, buf: &[u8], signature: &[u8]) -> Result<(), RsaError> {
+        match self {
+            Self::Rsa(key) => key.verify(buf, signature),
+            Self::Ed25519(key) => key.verify(buf, signature),
+        }
+    }
+}
+
+/// Private half of an SSH identity, either RSA or Ed25519.
+pub enum PrivKey {
+    Rsa(RsaPrivKey),
+    Ed25519(Ed25519PrivKey),
+}
+
+impl PrivKey {
+    /// Path of the default identity: `~/.ssh/id_ed25519`, falling back to `~/.ssh/id_rsa`.
+    pub fn default_path() -> Result<PathBuf, RsaError> {
+        let ssh_dir = std::env::home_dir()
+            .ok_or(RsaError::NoHomeDir)?
+            .join(".ssh");
+
+        ["id_ed25519", "id_rsa"]
+            .iter()
+            .map(|name| ssh_dir.join(name))
+            .find(|file| file.is_file())
+            .ok_or(RsaError::NoIdentity(ssh_dir))
+    }
+
+    /// Reads an unencrypted OpenSSH private key.
+    pub fn from_openssh(priv_key: &[u8]) -> Result<Self, RsaError> {
+        Self::from_openssh_with_passphrase(priv_key, || Err(RsaError::PassphraseRequired))
+    }
+
+    /// Reads an OpenSSH private key. If it is encrypted, `passphrase` is called once for the
+    /// passphrase to decrypt it with.
+    pub fn from_openssh_with_passphrase(
+        priv_key: &[u8],
+        passphrase: impl FnOnce() -> Result<Zeroizing<String>, RsaError>,
+    ) -> Result<Self, RsaError> {
+        let mut priv_key = ssh_key::PrivateKey::from_openssh(priv_key)?;
+
+        if priv_key.is_encrypted() {
+            let passphrase = passphrase()?;
+            priv_key = priv_key
+                .decrypt(passphrase.as_bytes())
+                .map_err(|err| match err {
+                    ssh_key::Error::Crypto => RsaError::WrongPassphrase,
+                    err => err.into(),
+                })?;
+        }
+
+        match priv_key.algorithm() {
+            Algorithm::Rsa { .. } => Ok(Self::Rsa(RsaPrivKey::from_ssh_key(&priv_key)?)),
+            Algorithm::Ed25519 => Ok(Self::Ed25519(Ed25519PrivKey::from_ssh_key(&priv_key)?)),
+            _ => Err(RsaError::KeyNotSupported),
+        }
+    }
+
+    pub fn from_file(file: &Path) -> Result<Self, RsaError> {
+        Self::from_openssh(&std::fs::read(file)?)
+    }
+
+    /// Reads an OpenSSH private key file, see [`Self::from_openssh_with_passphrase`].
+    pub fn from_file_with_passphrase(
+        file: &Path,
+        passphrase: impl FnOnce() -> Result<Zeroizing<String>, RsaError>,
+    ) -> Result<Self, RsaError> {
+        Self::from_openssh_with_passphrase(&std::fs::read(file)?, passphrase)
+    }
+
+    /// Decrypts a buffer produced by [`PubKey::seal`].
+    pub fn open(&self, buf: &[u8], padding: RsaPadding) -> Result<Vec<u8>, RsaError> {
+        match self {
+            Self::Rsa(key) => key.decrypt(buf, padding),
+            Self::Ed25519(key) => key.open(buf),
+        }
+    }
+
+    /// Signs `buf`: `rsa-sha2-256` for RSA keys, plain Ed25519 otherwise.
+    pub fn sign(&self, buf: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Rsa(key) => key.sign(buf),
+            Self::Ed25519(key) => key.sign(buf),
+        }
+    }
+
+    pub fn pub_key(&self) -> PubKey {
+        match self {
+            Self::Rsa(key) => PubKey::Rsa(key.pub_key()),
+            Self::Ed25519(key) => PubKey::Ed25519(key.pub_key()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    fn encrypted_ed25519(passphrase: &str) -> (String, String) {
+        let priv_key = ssh_key::PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let pub_key = priv_key.public_key().to_openssh().unwrap();
+        let encrypted = priv_key
+            .encrypt(&mut OsRng, passphrase)
+            .unwrap()
+            .to_openssh(ssh_key::LineEnding::LF)
+            .unwrap();
+
+        (encrypted.to_string(), pub_key)
+    }
+
+    #[test]
+    fn encrypted_identity() {
+        let (encrypted, pub_key) = encrypted_ed25519("hunter2");
+
+        let priv_key = PrivKey::from_openssh_with_passphrase(encrypted.as_bytes(), || {
+            Ok("hunter2".to_string().into())
+        })
+        .unwrap();
+        assert_eq!(priv_key.pub_key().to_openssh(None).unwrap(), pub_key);
+
+        assert!(matches!(
+            PrivKey::from_openssh_with_passphrase(encrypted.as_bytes(), || Ok("hunter3"
+                .to_string()
+                .into())),
+            Err(RsaError::WrongPassphrase)
+        ));
+        assert!(matches!(
+            PrivKey::from_openssh(encrypted.as_bytes()),
+            Err(RsaError::PassphraseRequired)
+        ));
+    }
+
+    #[test]
+    fn unencrypted_identity_skips_passphrase() {
+        let priv_key = Ed25519PrivKey::generate().to_openssh().unwrap();
+
+        assert!(
+            PrivKey::from_openssh_with_passphrase(priv_key.as_bytes(), || {
+                panic!("passphrase asked for an unencrypted key")
+            })
+            .is_ok()
+        );
+    }
+}
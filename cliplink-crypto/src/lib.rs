@@ -1,5 +1,19 @@
 mod aes;
+#[cfg(unix)]
+mod agent;
+mod auth;
+mod e2e;
+mod ed25519;
+mod key;
+mod kex;
 mod rsa;
 
 pub use aes::*;
+#[cfg(unix)]
+pub use agent::*;
+pub use auth::*;
+pub use e2e::*;
+pub use ed25519::*;
+pub use key::*;
+pub use kex::*;
 pub use rsa::*;
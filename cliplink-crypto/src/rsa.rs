@@ -1,5 +1,15 @@
-use rsa::{BigUint, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey, traits::PublicKeyParts};
-use ssh_key::{private::KeypairData, public::KeyData};
+use std::path::PathBuf;
+
+#[cfg(feature = "legacy-pkcs1v15")]
+use rsa::Pkcs1v15Encrypt;
+use rsa::{
+    BigUint, Oaep, RsaPrivateKey, RsaPublicKey,
+    pkcs1v15::{Signature, SigningKey, VerifyingKey},
+    signature::{SignatureEncoding, Signer, Verifier},
+    traits::PublicKeyParts,
+};
+use sha2::Sha256;
+use ssh_key::{PrivateKey, private::KeypairData, public::KeyData};
 
 #[derive(Debug, thiserror::Error)]
 pub enum RsaError {
@@ -9,6 +19,39 @@ pub enum RsaError {
     #[error("minimum key size is 2048 bytes")]
     MinimumKeySize2048,
 
+    #[error("key agreement failed")]
+    KeyAgreement,
+
+    #[error("key exchange mode {0} not supported")]
+    KexModeNotSupported(u8),
+
+    #[error("rsa padding {0} not supported")]
+    PaddingNotSupported(u8),
+
+    #[error("signature verification failed")]
+    SignatureVerification,
+
+    #[error("private key is encrypted and no passphrase was given")]
+    PassphraseRequired,
+
+    #[error("wrong passphrase")]
+    WrongPassphrase,
+
+    #[error("failed to read passphrase: {0}")]
+    Passphrase(String),
+
+    #[error("no identity found, expected id_ed25519 or id_rsa in {0:?}")]
+    NoIdentity(PathBuf),
+
+    #[error("home directory not found")]
+    NoHomeDir,
+
+    #[error(transparent)]
+    Aes(#[from] crate::AesError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
     #[error(transparent)]
     Utf8Error(#[from] std::str::Utf8Error),
 
@@ -19,6 +62,32 @@ pub enum RsaError {
     SshKeyError(#[from] ssh_key::Error),
 }
 
+/// Padding used to encrypt to RSA identities, negotiated by the client in `sshsyn`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RsaPadding {
+    /// RSAES-OAEP with SHA-256.
+    Oaep = 0,
+
+    /// RSAES-PKCS1-v1_5, open to padding-oracle attacks. Only available with the
+    /// `legacy-pkcs1v15` feature, for peers that predate OAEP.
+    #[cfg(feature = "legacy-pkcs1v15")]
+    Pkcs1v15 = 1,
+}
+
+impl TryFrom<u8> for RsaPadding {
+    type Error = RsaError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Oaep),
+            #[cfg(feature = "legacy-pkcs1v15")]
+            1 => Ok(Self::Pkcs1v15),
+            padding => Err(RsaError::PaddingNotSupported(padding)),
+        }
+    }
+}
+
 pub struct RsaPubKey(RsaPublicKey);
 
 impl RsaPubKey {
@@ -48,33 +117,46 @@ impl RsaPubKey {
         Ok(pub_key.to_openssh()?)
     }
 
+    pub fn encrypt(&self, buf: &[u8], padding: RsaPadding) -> Result<Vec<u8>, RsaError> {
+        match padding {
+            RsaPadding::Oaep => self.encrypt_oaep(buf),
+            #[cfg(feature = "legacy-pkcs1v15")]
+            RsaPadding::Pkcs1v15 => self.encrypt_pkcs1v15(buf),
+        }
+    }
+
+    /// Encrypts with RSAES-OAEP, SHA-256 for both the label hash and MGF1.
+    pub fn encrypt_oaep(&self, buf: &[u8]) -> Result<Vec<u8>, RsaError> {
+        let mut rng = rand::thread_rng();
+        Ok(self.0.encrypt(&mut rng, Oaep::new::<Sha256>(), buf)?)
+    }
+
+    #[cfg(feature = "legacy-pkcs1v15")]
     pub fn encrypt_pkcs1v15(&self, buf: &[u8]) -> Result<Vec<u8>, RsaError> {
         let mut rng = rand::thread_rng();
         Ok(self.0.encrypt(&mut rng, Pkcs1v15Encrypt, buf)?)
     }
-}
-
-pub struct RsaPrivKey(RsaPrivateKey);
 
-impl Default for RsaPrivKey {
-    fn default() -> Self {
-        let file = std::env::home_dir()
-            .expect("home dir not found, os mode unsupported")
-            .join(".ssh/id_rsa");
+    /// Verifies an RSASSA-PKCS1-v1_5 SHA-256 (`rsa-sha2-256`) signature.
+    pub fn verify(&self, buf: &[u8], signature: &[u8]) -> Result<(), RsaError> {
+        let signature =
+            Signature::try_from(signature).map_err(|_| RsaError::SignatureVerification)?;
 
-        if !file.exists() || !file.is_file() {
-            panic!("id_rsa not available at {file:?}");
-        }
-
-        let file = std::fs::read(file).unwrap();
-        Self::from_openssh(&file).unwrap()
+        VerifyingKey::<Sha256>::new(self.0.clone())
+            .verify(buf, &signature)
+            .map_err(|_| RsaError::SignatureVerification)
     }
 }
 
+pub struct RsaPrivKey(RsaPrivateKey);
+
 impl RsaPrivKey {
     pub fn from_openssh(priv_key: &[u8]) -> Result<Self, RsaError> {
-        let priv_key = ssh_key::private::PrivateKey::from_openssh(priv_key)?;
+        Self::from_ssh_key(&PrivateKey::from_openssh(priv_key)?)
+    }
 
+    /// Converts a decrypted `ssh-key` private key.
+    pub(crate) fn from_ssh_key(priv_key: &PrivateKey) -> Result<Self, RsaError> {
         let rsa = match priv_key.key_data() {
             KeypairData::Rsa(key) => {
                 // TODO: `ssh-key 0.6.7` fixed in the rc, but current version is wrong
@@ -100,8 +182,26 @@ impl RsaPrivKey {
         Ok(Self(rsa))
     }
 
+    pub fn decrypt(&self, buf: &[u8], padding: RsaPadding) -> Result<Vec<u8>, RsaError> {
+        match padding {
+            RsaPadding::Oaep => self.decrypt_oaep(buf),
+            #[cfg(feature = "legacy-pkcs1v15")]
+            RsaPadding::Pkcs1v15 => self.decrypt_pkcs1v15(buf),
+        }
+    }
+
+    pub fn decrypt_oaep(&self, buf: &[u8]) -> Result<Vec<u8>, RsaError> {
+        Ok(self.0.decrypt(Oaep::new::<Sha256>(), buf)?)
+    }
+
+    #[cfg(feature = "legacy-pkcs1v15")]
     pub fn decrypt_pkcs1v15(&self, buf: &[u8]) -> Result<Vec<u8>, RsaError> {
-        Ok(self.0.decrypt(Pkcs1v15Encrypt, &buf)?)
+        Ok(self.0.decrypt(Pkcs1v15Encrypt, buf)?)
+    }
+
+    /// Signs with RSASSA-PKCS1-v1_5 SHA-256 (`rsa-sha2-256`).
+    pub fn sign(&self, buf: &[u8]) -> Vec<u8> {
+        SigningKey::<Sha256>::new(self.0.clone()).sign(buf).to_vec()
     }
 
     pub fn pub_key(&self) -> RsaPubKey {
@@ -113,7 +213,7 @@ impl RsaPrivKey {
 mod test {
     use std::sync::OnceLock;
 
-    use crate::{RsaPrivKey, RsaPubKey};
+    use crate::{RsaPadding, RsaPrivKey, RsaPubKey};
 
     fn rsa_keypair_2048() -> (rsa::RsaPrivateKey, rsa::RsaPublicKey, String, String) {
         let mut rng = rand::thread_rng();
@@ -145,9 +245,9 @@ mod test {
         let pub_key = RsaPubKey::from_openssh(pub_key_openssh.as_bytes()).unwrap();
 
         let plain = "my plain text";
-        let enc_buf = pub_key.encrypt_pkcs1v15(plain.as_bytes()).unwrap();
+        let enc_buf = pub_key.encrypt_oaep(plain.as_bytes()).unwrap();
         let dec_buf = rsa_priv_key
-            .decrypt(rsa::Pkcs1v15Encrypt, &enc_buf)
+            .decrypt(rsa::Oaep::new::<sha2::Sha256>(), &enc_buf)
             .unwrap();
 
         assert_ne!(enc_buf, plain.as_bytes());
@@ -163,11 +263,60 @@ mod test {
         let plain = "my plain text";
         let mut rng = rand::thread_rng();
         let enc_buf = rsa_pub_key
-            .encrypt(&mut rng, rsa::Pkcs1v15Encrypt, plain.as_bytes())
+            .encrypt(&mut rng, rsa::Oaep::new::<sha2::Sha256>(), plain.as_bytes())
             .unwrap();
-        let dec_buf = priv_key.decrypt_pkcs1v15(&enc_buf).unwrap();
+        let dec_buf = priv_key.decrypt_oaep(&enc_buf).unwrap();
 
         assert_ne!(enc_buf, plain.as_bytes());
         assert_eq!(dec_buf, plain.as_bytes());
     }
+
+    #[test]
+    fn padding_negotiation() {
+        let (_, _, priv_key_openssh, pub_key_openssh) = rsa_keypair_2048();
+
+        let priv_key = RsaPrivKey::from_openssh(priv_key_openssh.as_bytes()).unwrap();
+        let pub_key = RsaPubKey::from_openssh(pub_key_openssh.as_bytes()).unwrap();
+
+        let enc_buf = pub_key.encrypt(b"session key", RsaPadding::Oaep).unwrap();
+        assert_eq!(
+            priv_key.decrypt(&enc_buf, RsaPadding::Oaep).unwrap(),
+            b"session key"
+        );
+
+        #[cfg(not(feature = "legacy-pkcs1v15"))]
+        assert!(matches!(
+            RsaPadding::try_from(1),
+            Err(crate::RsaError::PaddingNotSupported(1))
+        ));
+    }
+
+    #[cfg(feature = "legacy-pkcs1v15")]
+    #[test]
+    fn asymmetric_pkcs1v15() {
+        let (_, _, priv_key_openssh, pub_key_openssh) = rsa_keypair_2048();
+
+        let priv_key = RsaPrivKey::from_openssh(priv_key_openssh.as_bytes()).unwrap();
+        let pub_key = RsaPubKey::from_openssh(pub_key_openssh.as_bytes()).unwrap();
+
+        let enc_buf = pub_key.encrypt_pkcs1v15(b"my plain text").unwrap();
+        assert_eq!(
+            priv_key.decrypt_pkcs1v15(&enc_buf).unwrap(),
+            b"my plain text"
+        );
+        assert!(priv_key.decrypt_oaep(&enc_buf).is_err());
+    }
+
+    #[test]
+    fn sign_verify() {
+        let (_, _, priv_key_openssh, pub_key_openssh) = rsa_keypair_2048();
+
+        let priv_key = RsaPrivKey::from_openssh(priv_key_openssh.as_bytes()).unwrap();
+        let pub_key = RsaPubKey::from_openssh(pub_key_openssh.as_bytes()).unwrap();
+
+        let signature = priv_key.sign(b"challenge");
+
+        pub_key.verify(b"challenge", &signature).unwrap();
+        assert!(pub_key.verify(b"challengf", &signature).is_err());
+    }
 }
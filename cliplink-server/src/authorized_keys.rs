@@ -0,0 +1,451 @@
+use std::{collections::HashMap, net::IpAddr, path::PathBuf, sync::RwLock, time::SystemTime};
+
+use cliplink_crypto::{PubKey, RsaError};
+use ssh_key::authorized_keys::Entry;
+use tracing::{error, info, warn};
+
+use crate::repository::unix_time;
+
+/// Options that turn off SSH features cliplink doesn't offer in the first place, so they already
+/// hold for every key.
+const NO_OP_OPTIONS: &[&str] = &[
+    "restrict",
+    "no-agent-forwarding",
+    "no-port-forwarding",
+    "no-pty",
+    "no-user-rc",
+    "no-x11-forwarding",
+];
+
+/// Why a key was turned away, sent back in `sshsyndeny`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    NotAuthorized,
+    FromNotAllowed,
+    Expired,
+    UnsupportedOption,
+}
+
+impl Denied {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::NotAuthorized => "key not authorized",
+            Self::FromNotAllowed => "key not authorized from this address",
+            Self::Expired => "key expired",
+            Self::UnsupportedOption => "key has an option the server doesn't support",
+        }
+    }
+}
+
+/// A key listed in `authorized_keys`, along with the options that apply to it.
+#[derive(Debug, Clone)]
+pub struct AuthorizedKey {
+    pub comment: String,
+
+    /// `from="pattern-list"`: addresses the key may connect from.
+    from: Option<String>,
+
+    /// `expiry-time="YYYYMMDD[HHMM[SS]]"`, in UTC: when the key stops being accepted, as seconds
+    /// since the Unix epoch.
+    expires_at: Option<u64>,
+
+    /// An option the server doesn't support, or can't make sense of, which denies the key: a
+    /// restriction it can't enforce must not be skipped over.
+    unsupported: Option<String>,
+}
+
+impl AuthorizedKey {
+    fn from_entry(entry: &Entry) -> Self {
+        let expiry_time = option(entry, "expiry-time");
+        let expires_at = expiry_time.as_deref().and_then(parse_expiry_time);
+
+        // An expiry time that doesn't parse mustn't leave the key valid forever.
+        let invalid_expiry_time = expiry_time.is_some() && expires_at.is_none();
+        let unsupported = entry
+            .config_opts()
+            .iter()
+            .map(|opt| opt.split_once('=').map_or(opt, |(name, _)| name))
+            .find(|name| {
+                !["from", "expiry-time"]
+                    .iter()
+                    .chain(NO_OP_OPTIONS)
+                    .any(|known| name.eq_ignore_ascii_case(known))
+            })
+            .or(invalid_expiry_time.then_some("expiry-time"))
+            .map(str::to_string);
+
+        Self {
+            comment: entry.public_key().comment().to_string(),
+            from: option(entry, "from"),
+            expires_at,
+            unsupported,
+        }
+    }
+
+    fn allows(&self, peer: IpAddr) -> bool {
+        self.from
+            .as_deref()
+            .is_none_or(|patterns| match_pattern_list(patterns, peer))
+    }
+
+    fn check(&self, peer: IpAddr, now: u64) -> Result<(), Denied> {
+        if self.unsupported.is_some() {
+            return Err(Denied::UnsupportedOption);
+        }
+        if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(Denied::Expired);
+        }
+        if !self.allows(peer) {
+            return Err(Denied::FromNotAllowed);
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses an OpenSSH `expiry-time` value, `YYYYMMDD[HHMM[SS]]`, as UTC.
+fn parse_expiry_time(time: &str) -> Option<u64> {
+    if !matches!(time.len(), 8 | 12 | 14) || !time.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let field = |range: std::ops::Range<usize>| time.get(range).map_or(Some(0), |f| f.parse().ok());
+    let (year, month, day): (i64, i64, i64) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, minute, second): (i64, i64, i64) = (field(8..10)?, field(10..12)?, field(12..14)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    // Days since 1970-01-01 in the proleptic Gregorian calendar, from Howard Hinnant's
+    // `days_from_civil`.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    u64::try_from(days * 86_400 + hour * 3_600 + minute * 60 + second).ok()
+}
+
+/// Value of the `name="value"` option of `entry`.
+fn option(entry: &Entry, name: &str) -> Option<String> {
+    entry
+        .config_opts()
+        .iter()
+        .filter_map(|opt| opt.split_once('='))
+        .find(|(opt, _)| opt.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim_matches('"').to_string())
+}
+
+/// Matches `peer` against a comma-separated `from=` pattern list: `*` and `?` wildcards,
+/// `addr/len` CIDR blocks, and `!` negations, which win over any positive match.
+fn match_pattern_list(patterns: &str, peer: IpAddr) -> bool {
+    let mut matched = false;
+
+    for pattern in patterns.split(',').map(str::trim) {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+
+        if match_pattern(pattern, peer) {
+            if negated {
+                return false;
+            }
+            matched = true;
+        }
+    }
+
+    matched
+}
+
+fn match_pattern(pattern: &str, peer: IpAddr) -> bool {
+    if let Some((addr, len)) = pattern.split_once('/') {
+        let (Ok(addr), Ok(len)) = (addr.parse::<IpAddr>(), len.parse::<u32>()) else {
+            return false;
+        };
+
+        return match (addr, peer) {
+            (IpAddr::V4(addr), IpAddr::V4(peer)) if len <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+                u32::from(addr) & mask == u32::from(peer) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(peer)) if len <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+                u128::from(addr) & mask == u128::from(peer) & mask
+            }
+            _ => false,
+        };
+    }
+
+    match_glob(pattern.as_bytes(), peer.to_string().as_bytes())
+}
+
+fn match_glob(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            match_glob(&pattern[1..], text) || (!text.is_empty() && match_glob(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(_)) => match_glob(&pattern[1..], &text[1..]),
+        (Some(p), Some(t)) if p.eq_ignore_ascii_case(t) => match_glob(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+#[derive(Default)]
+struct Loaded {
+    modified: Option<SystemTime>,
+    keys: HashMap<String, AuthorizedKey>,
+}
+
+/// OpenSSH `authorized_keys` allowlist, reloaded whenever the file changes on disk.
+///
+/// A missing or empty file denies every key. Keys may carry the `from` and `expiry-time`
+/// options, along with `restrict` and the `no-*` options, which turn off nothing cliplink
+/// offers. A key with any other option is denied rather than let in unrestricted.
+pub struct AuthorizedKeys {
+    path: PathBuf,
+    loaded: RwLock<Loaded>,
+}
+
+impl AuthorizedKeys {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            loaded: RwLock::default(),
+        }
+    }
+
+    /// Looks `pub_key` up for a connection from `peer`.
+    pub fn authorize(&self, pub_key: &PubKey, peer: IpAddr) -> Result<AuthorizedKey, Denied> {
+        self.reload();
+
+        let pub_key = pub_key
+            .to_openssh(None)
+            .map_err(|_| Denied::NotAuthorized)?;
+        let loaded = self.loaded.read().expect("authorized keys lock poisoned");
+
+        let key = loaded.keys.get(&pub_key).ok_or(Denied::NotAuthorized)?;
+        key.check(peer, unix_time())?;
+
+        Ok(key.clone())
+    }
+
+    /// Parses the file as [`Self::authorize`] would, returning how many keys it lists.
+    pub fn check(&self) -> Result<usize, RsaError> {
+        self.read_keys().map(|keys| keys.len())
+    }
+
+    fn reload(&self) {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+
+        if self
+            .loaded
+            .read()
+            .expect("authorized keys lock poisoned")
+            .modified
+            == modified
+        {
+            return;
+        }
+
+        let mut loaded = self.loaded.write().expect("authorized keys lock poisoned");
+        loaded.modified = modified;
+
+        match self.read_keys() {
+            Ok(keys) => {
+                info!("loaded {} authorized keys from {:?}", keys.len(), self.path);
+                for key in keys.values() {
+                    if let Some(option) = &key.unsupported {
+                        warn!(
+                            "denying key {:?}: unsupported option {option:?}",
+                            key.comment
+                        );
+                    }
+                }
+                loaded.keys = keys;
+            }
+            Err(err) => {
+                error!("failed to load authorized keys from {:?}: {err}", self.path);
+                loaded.keys.clear();
+            }
+        }
+    }
+
+    fn read_keys(&self) -> Result<HashMap<String, AuthorizedKey>, RsaError> {
+        if !self.path.exists() {
+            return Ok(HashMap::new());
+        }
+
+        ssh_key::AuthorizedKeys::read_file(&self.path)?
+            .iter()
+            .map(|entry| {
+                let pub_key = ssh_key::PublicKey::new(entry.public_key().key_data().clone(), "");
+                Ok::<_, RsaError>((pub_key.to_openssh()?, AuthorizedKey::from_entry(entry)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, path::Path, time::Duration};
+
+    use cliplink_crypto::{Ed25519PrivKey, PrivKey};
+
+    use super::*;
+
+    fn gen_key() -> PubKey {
+        PrivKey::Ed25519(Ed25519PrivKey::generate()).pub_key()
+    }
+
+    /// Writes `lines` to `path`, dated `modified` seconds after the epoch so the change is seen
+    /// regardless of the file system's timestamp granularity.
+    fn write_keys(path: &Path, lines: &[String], modified: u64) {
+        std::fs::write(path, lines.join("\n")).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(modified))
+            .unwrap();
+    }
+
+    fn line(options: &str, key: &PubKey, comment: &str) -> String {
+        format!("{options} {} {comment}", key.to_openssh(None).unwrap())
+            .trim_start()
+            .to_string()
+    }
+
+    #[test]
+    fn from_patterns() {
+        let peer: IpAddr = "192.168.1.20".parse().unwrap();
+
+        assert!(match_pattern_list("192.168.1.*", peer));
+        assert!(match_pattern_list("10.0.0.1,192.168.1.2?", peer));
+        assert!(match_pattern_list("192.168.0.0/16", peer));
+        assert!(!match_pattern_list("192.168.0.0/24", peer));
+        assert!(!match_pattern_list("192.168.1.*,!192.168.1.20", peer));
+        assert!(!match_pattern_list("", peer));
+        assert!(match_pattern_list("::1", "::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn options() {
+        let entry: Entry = "from=\"10.0.0.*\",no-pty ssh-ed25519 \
+                            AAAAC3NzaC1lZDI1NTE5AAAAILM+rvN+ot98qgEN796jTiQfZfG1KaT0PtFDJ/XFSqti me"
+            .parse()
+            .unwrap();
+        let key = AuthorizedKey::from_entry(&entry);
+
+        assert_eq!(key.comment, "me");
+        assert!(key.allows("10.0.0.7".parse().unwrap()));
+        assert!(!key.allows("10.0.1.7".parse().unwrap()));
+        assert!(key.unsupported.is_none());
+
+        let key = gen_key();
+        let unsupported = |options: &str| {
+            let entry: Entry = line(options, &key, "me").parse().unwrap();
+            AuthorizedKey::from_entry(&entry).unsupported
+        };
+        assert_eq!(unsupported("restrict,no-pty"), None);
+        assert_eq!(unsupported("expiry-time=\"20991231\""), None);
+        assert_eq!(
+            unsupported("history-depth=\"3\""),
+            Some("history-depth".to_string())
+        );
+        assert_eq!(
+            unsupported("command=\"/bin/true\""),
+            Some("command".to_string())
+        );
+        assert_eq!(
+            unsupported("restrict,permitopen=\"localhost:80\""),
+            Some("permitopen".to_string())
+        );
+        assert_eq!(
+            unsupported("expiry-time=\"tomorrow\""),
+            Some("expiry-time".to_string())
+        );
+    }
+
+    #[test]
+    fn expiry_times() {
+        assert_eq!(parse_expiry_time("19700101"), Some(0));
+        assert_eq!(parse_expiry_time("20000301"), Some(951_868_800));
+        assert_eq!(parse_expiry_time("202401021530"), Some(1_704_209_400));
+        assert_eq!(parse_expiry_time("20991231235959"), Some(4_102_444_799));
+        assert_eq!(parse_expiry_time("20241301"), None);
+        assert_eq!(parse_expiry_time("2024010"), None);
+        assert_eq!(parse_expiry_time("2024-1-01"), None);
+    }
+
+    #[test]
+    fn denies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("authorized_keys");
+        let peer: IpAddr = "10.0.0.7".parse().unwrap();
+        let keys: Vec<_> = (0..4).map(|_| gen_key()).collect();
+
+        write_keys(
+            &path,
+            &[
+                line("", &keys[0], "plain"),
+                line("from=\"192.168.0.0/16\"", &keys[1], "lan"),
+                line("expiry-time=\"20200101\"", &keys[2], "expired"),
+                line("command=\"/bin/true\"", &keys[3], "forced"),
+            ],
+            1000,
+        );
+        let authorized_keys = AuthorizedKeys::new(path);
+
+        assert_eq!(authorized_keys.check().unwrap(), 4);
+        assert_eq!(
+            authorized_keys.authorize(&keys[0], peer).unwrap().comment,
+            "plain"
+        );
+        let denied = |key: &PubKey| authorized_keys.authorize(key, peer).unwrap_err();
+        assert_eq!(denied(&keys[1]), Denied::FromNotAllowed);
+        assert_eq!(denied(&keys[2]), Denied::Expired);
+        assert_eq!(denied(&keys[3]), Denied::UnsupportedOption);
+        assert_eq!(denied(&gen_key()), Denied::NotAuthorized);
+    }
+
+    #[test]
+    fn reloads_on_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("authorized_keys");
+        let peer: IpAddr = "127.0.0.1".parse().unwrap();
+        let (first, second) = (gen_key(), gen_key());
+        let authorized_keys = AuthorizedKeys::new(path.clone());
+
+        // A missing file denies every key.
+        assert_eq!(
+            authorized_keys.authorize(&first, peer).unwrap_err(),
+            Denied::NotAuthorized
+        );
+
+        write_keys(&path, &[line("", &first, "first")], 1000);
+        assert!(authorized_keys.authorize(&first, peer).is_ok());
+        assert!(authorized_keys.authorize(&second, peer).is_err());
+
+        write_keys(&path, &[line("", &second, "second")], 2000);
+        assert!(authorized_keys.authorize(&first, peer).is_err());
+        assert!(authorized_keys.authorize(&second, peer).is_ok());
+
+        // A file that no longer parses denies every key, rather than keeping the old ones.
+        write_keys(&path, &["not a key".to_string()], 3000);
+        assert!(authorized_keys.authorize(&second, peer).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(authorized_keys.authorize(&second, peer).is_err());
+    }
+}
@@ -0,0 +1,557 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use cliplink_common::{Config, parse_duration};
+use serde::{Deserialize, Deserializer};
+use tracing_subscriber::EnvFilter;
+
+/// Name of the server config file, in the system and user config directories.
+pub const CONFIG_FILE: &str = "server.toml";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("invalid config file {path:?}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: Box<toml::de::Error>,
+    },
+
+    #[error("invalid {key} {value:?}: {reason}")]
+    Invalid {
+        key: &'static str,
+        value: String,
+        reason: String,
+    },
+}
+
+impl ConfigError {
+    pub fn invalid(key: &'static str, value: impl Display, reason: impl Display) -> Self {
+        Self::Invalid {
+            key,
+            value: value.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Where clips are kept.
+#[derive(Debug, Clone)]
+pub enum Storage {
+    /// Lost on restart.
+    Memory,
+
+    /// One file per clip under `data_dir`, `~/.local/share/cliplink/data` by default.
+    File { data_dir: PathBuf },
+
+    /// A single SQLite database.
+    Sqlite { path: PathBuf },
+}
+
+/// Storage backend, as named in config files, `CL_STORAGE` and `--storage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    Memory,
+    File,
+    Sqlite,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(storage: &str) -> Result<Self, Self::Err> {
+        match storage {
+            "memory" => Ok(Self::Memory),
+            "file" => Ok(Self::File),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err("expected \"memory\", \"file\" or \"sqlite\"".to_string()),
+        }
+    }
+}
+
+/// One source of settings: a config file, the environment or the command line. Settings left
+/// unset fall through to the sources below it.
+///
+/// Config files use the field names in kebab-case, e.g. `max-clip-size = 1048576`, durations
+/// being strings such as `"1h30m"`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigLayer {
+    pub addrs: Option<Vec<IpAddr>>,
+    pub port: Option<u16>,
+    pub storage: Option<StorageKind>,
+    pub data_dir: Option<PathBuf>,
+    pub max_clip_size: Option<u64>,
+    pub history_depth: Option<usize>,
+
+    /// Per-identity overrides of `history-depth`, by SHA256 key fingerprint, as printed by
+    /// `ssh-keygen -l`. Only read from config files.
+    pub key_history_depth: Option<HashMap<String, usize>>,
+    #[serde(deserialize_with = "duration")]
+    pub max_ttl: Option<Duration>,
+    pub max_connections: Option<usize>,
+    #[serde(deserialize_with = "duration")]
+    pub idle_timeout: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub read_timeout: Option<Duration>,
+    pub host_key: Option<PathBuf>,
+    pub authorized_keys: Option<PathBuf>,
+
+    /// `tracing` filter directives, e.g. `"info"` or `"warn,cliplink_server=debug"`.
+    pub log: Option<String>,
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let duration = String::deserialize(deserializer)?;
+    parse_duration(&duration)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl ConfigLayer {
+    /// Reads a TOML config file.
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let toml = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        Self::from_toml(path, &toml)
+    }
+
+    fn from_toml(path: &Path, toml: &str) -> Result<Self, ConfigError> {
+        toml::from_str(toml).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source: Box::new(source),
+        })
+    }
+
+    /// Reads the `CL_*` environment variables.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        fn parse<T: FromStr<Err: Display>>(
+            var: &impl Fn(&str) -> Option<String>,
+            name: &'static str,
+        ) -> Result<Option<T>, ConfigError> {
+            var(name)
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|err| ConfigError::invalid(name, &value, err))
+                })
+                .transpose()
+        }
+
+        fn duration(
+            var: &impl Fn(&str) -> Option<String>,
+            name: &'static str,
+        ) -> Result<Option<Duration>, ConfigError> {
+            var(name)
+                .map(|value| {
+                    parse_duration(&value).map_err(|err| ConfigError::invalid(name, &value, err))
+                })
+                .transpose()
+        }
+
+        Ok(Self {
+            addrs: var("CL_ADDR")
+                .map(|addrs| {
+                    addrs
+                        .split(',')
+                        .map(|addr| {
+                            addr.trim()
+                                .parse()
+                                .map_err(|err| ConfigError::invalid("CL_ADDR", &addrs, err))
+                        })
+                        .collect()
+                })
+                .transpose()?,
+            port: parse(&var, "CL_PORT")?,
+            storage: parse(&var, "CL_STORAGE")?,
+            data_dir: var("CL_DATA_DIR").map(PathBuf::from),
+            max_clip_size: parse(&var, "CL_MAX_CLIP_SIZE")?,
+            history_depth: parse(&var, "CL_HISTORY_DEPTH")?,
+            key_history_depth: None,
+            max_ttl: duration(&var, "CL_MAX_TTL")?,
+            max_connections: parse(&var, "CL_MAX_CONNECTIONS")?,
+            idle_timeout: duration(&var, "CL_IDLE_TIMEOUT")?,
+            read_timeout: duration(&var, "CL_READ_TIMEOUT")?,
+            host_key: var("CL_HOST_KEY").map(PathBuf::from),
+            authorized_keys: var("CL_AUTHORIZED_KEYS").map(PathBuf::from),
+            log: var("CL_LOG"),
+        })
+    }
+
+    /// Takes the settings of `self`, falling back to `lower` for those it leaves unset.
+    pub fn or(self, lower: Self) -> Self {
+        Self {
+            addrs: self.addrs.or(lower.addrs),
+            port: self.port.or(lower.port),
+            storage: self.storage.or(lower.storage),
+            data_dir: self.data_dir.or(lower.data_dir),
+            max_clip_size: self.max_clip_size.or(lower.max_clip_size),
+            history_depth: self.history_depth.or(lower.history_depth),
+            key_history_depth: self.key_history_depth.or(lower.key_history_depth),
+            max_ttl: self.max_ttl.or(lower.max_ttl),
+            max_connections: self.max_connections.or(lower.max_connections),
+            idle_timeout: self.idle_timeout.or(lower.idle_timeout),
+            read_timeout: self.read_timeout.or(lower.read_timeout),
+            host_key: self.host_key.or(lower.host_key),
+            authorized_keys: self.authorized_keys.or(lower.authorized_keys),
+            log: self.log.or(lower.log),
+        }
+    }
+}
+
+/// Server settings.
+///
+/// Each setting is taken from the first source that sets it:
+///
+/// 1. command line flags
+/// 2. `CL_*` environment variables
+/// 3. the user config file, `~/.config/cliplink/server.toml`
+/// 4. the system config file, `/etc/cliplink/server.toml`
+/// 5. built-in defaults
+///
+/// `--config <file>` reads that file in place of the user and system ones.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Addresses to listen on, all on the same port.
+    pub addrs: Vec<IpAddr>,
+    pub port: u16,
+
+    /// Largest clip (in bytes) accepted by `paste`.
+    pub max_clip_size: u64,
+
+    /// Entries kept per clip, unless `key_history_depth` says otherwise for the identity.
+    pub history_depth: usize,
+
+    /// Entries kept per clip for the identities listed, by SHA256 key fingerprint.
+    pub key_history_depth: HashMap<String, usize>,
+
+    /// Longest a clip may live. Pastes without a TTL get this one, longer TTLs are capped to it.
+    pub max_ttl: Option<Duration>,
+
+    /// Connections served at once. Further connections are closed as soon as they are accepted.
+    pub max_connections: usize,
+
+    /// Longest a session may wait for the next request before it is closed.
+    pub idle_timeout: Duration,
+
+    /// Longest a single frame may take to arrive or be sent once the exchange has started.
+    pub read_timeout: Duration,
+
+    /// OpenSSH private key identifying this server, generated on first start.
+    pub host_key: PathBuf,
+
+    /// OpenSSH `authorized_keys` file listing the identities allowed to connect.
+    pub authorized_keys: PathBuf,
+
+    pub storage: Storage,
+
+    /// `tracing` filter directives for the server log.
+    pub log: String,
+
+    /// Config files the settings were read from.
+    pub files: Vec<PathBuf>,
+}
+
+impl ServerConfig {
+    const DEFAULT_PORT: u16 = 6166;
+    const DEFAULT_MAX_CLIP_SIZE: u64 = 8 * 1024 * 1024; // 8 MiB
+    const DEFAULT_HISTORY_DEPTH: usize = 10;
+    const DEFAULT_MAX_CONNECTIONS: usize = 256;
+    const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+    const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+    const DEFAULT_LOG: &str = "info";
+
+    /// Loads the settings from every source, `flags` taking precedence over all others.
+    pub fn load(config_file: Option<&Path>, flags: ConfigLayer) -> Result<Self, ConfigError> {
+        Self::load_with_env(config_file, flags, ConfigLayer::from_env()?)
+    }
+
+    /// Like [`Self::load`], with `env` in place of the process environment.
+    fn load_with_env(
+        config_file: Option<&Path>,
+        flags: ConfigLayer,
+        env: ConfigLayer,
+    ) -> Result<Self, ConfigError> {
+        let mut files = Vec::new();
+        let mut layer = ConfigLayer::default();
+
+        match config_file {
+            Some(path) => {
+                layer = ConfigLayer::from_file(path)?;
+                files.push(path.to_path_buf());
+            }
+            None => {
+                let paths = [
+                    Some(Config::system_file_path(CONFIG_FILE)),
+                    Config::user_file_path(CONFIG_FILE),
+                ];
+
+                for path in paths.into_iter().flatten() {
+                    if path.is_file() {
+                        layer = ConfigLayer::from_file(&path)?.or(layer);
+                        files.push(path);
+                    }
+                }
+            }
+        }
+
+        let mut config = Self::resolve(flags.or(env).or(layer))?;
+        config.files = files;
+
+        Ok(config)
+    }
+
+    /// Fills in the defaults for the settings `layer` leaves unset, and validates it.
+    fn resolve(layer: ConfigLayer) -> Result<Self, ConfigError> {
+        fn positive(key: &'static str, value: usize) -> Result<usize, ConfigError> {
+            match value {
+                0 => Err(ConfigError::invalid(key, value, "must be at least 1")),
+                value => Ok(value),
+            }
+        }
+
+        fn non_zero(key: &'static str, value: Duration) -> Result<Duration, ConfigError> {
+            match value.is_zero() {
+                true => Err(ConfigError::invalid(key, "0s", "must not be zero")),
+                false => Ok(value),
+            }
+        }
+
+        let addrs = layer
+            .addrs
+            .unwrap_or_else(|| vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        if addrs.is_empty() {
+            return Err(ConfigError::invalid("addrs", "[]", "expected an address"));
+        }
+
+        let log = layer.log.unwrap_or_else(|| Self::DEFAULT_LOG.to_string());
+        if let Err(err) = EnvFilter::try_new(&log) {
+            return Err(ConfigError::invalid("log", log, err));
+        }
+
+        let key_history_depth = layer
+            .key_history_depth
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(fingerprint, depth)| Ok((fingerprint, positive("key-history-depth", depth)?)))
+            .collect::<Result<_, ConfigError>>()?;
+
+        let data_dir = || {
+            layer
+                .data_dir
+                .clone()
+                .or_else(|| Config::user_data_path("data"))
+                .ok_or_else(|| {
+                    ConfigError::invalid("data-dir", "", "not set, and there is no home directory")
+                })
+        };
+
+        let storage = match layer.storage.unwrap_or(StorageKind::Memory) {
+            StorageKind::Memory => Storage::Memory,
+            StorageKind::File => Storage::File {
+                data_dir: data_dir()?,
+            },
+            StorageKind::Sqlite => Storage::Sqlite {
+                path: data_dir()?.join("cliplink.sqlite3"),
+            },
+        };
+
+        Ok(Self {
+            addrs,
+            port: layer.port.unwrap_or(Self::DEFAULT_PORT),
+            max_clip_size: layer.max_clip_size.unwrap_or(Self::DEFAULT_MAX_CLIP_SIZE),
+            history_depth: positive(
+                "history-depth",
+                layer.history_depth.unwrap_or(Self::DEFAULT_HISTORY_DEPTH),
+            )?,
+            key_history_depth,
+            max_ttl: layer.max_ttl,
+            max_connections: positive(
+                "max-connections",
+                layer
+                    .max_connections
+                    .unwrap_or(Self::DEFAULT_MAX_CONNECTIONS),
+            )?,
+            idle_timeout: non_zero(
+                "idle-timeout",
+                layer.idle_timeout.unwrap_or(Self::DEFAULT_IDLE_TIMEOUT),
+            )?,
+            read_timeout: non_zero(
+                "read-timeout",
+                layer.read_timeout.unwrap_or(Self::DEFAULT_READ_TIMEOUT),
+            )?,
+            host_key: layer
+                .host_key
+                .unwrap_or_else(|| Config::system_file_path("host_key")),
+            authorized_keys: layer
+                .authorized_keys
+                .unwrap_or_else(|| Config::system_file_path("authorized_keys")),
+            storage,
+            log,
+            files: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Result<ConfigLayer, ConfigError> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        ConfigLayer::from_vars(|name| vars.get(name).map(|value| value.to_string()))
+    }
+
+    #[test]
+    fn precedence() {
+        let system = ConfigLayer::from_toml(
+            Path::new("system.toml"),
+            r#"
+                addrs = ["0.0.0.0", "::"]
+                port = 7000
+                max-ttl = "1d"
+                storage = "sqlite"
+                data-dir = "/srv/cliplink"
+
+                [key-history-depth]
+                "SHA256:zmVQF0p0Z1jzkXgBX0GRA9cWCJlk4o8C6D8KOwjzPb4" = 3
+            "#,
+        )
+        .unwrap();
+        let user = ConfigLayer::from_toml(Path::new("user.toml"), "port = 7001\nmax-ttl = \"2h\"")
+            .unwrap();
+        let env = vars(&[("CL_PORT", "7002"), ("CL_IDLE_TIMEOUT", "90")]).unwrap();
+        let flags = ConfigLayer {
+            port: Some(7003),
+            ..Default::default()
+        };
+
+        let config = ServerConfig::resolve(flags.or(env).or(user.or(system))).unwrap();
+
+        assert_eq!(config.addrs.len(), 2);
+        assert_eq!(config.port, 7003);
+        assert_eq!(config.max_ttl, Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(config.idle_timeout, Duration::from_secs(90));
+        assert_eq!(config.read_timeout, ServerConfig::DEFAULT_READ_TIMEOUT);
+        assert_eq!(
+            config.key_history_depth,
+            HashMap::from([(
+                "SHA256:zmVQF0p0Z1jzkXgBX0GRA9cWCJlk4o8C6D8KOwjzPb4".to_string(),
+                3
+            )])
+        );
+        assert!(matches!(
+            config.storage,
+            Storage::Sqlite { path } if path == Path::new("/srv/cliplink/cliplink.sqlite3")
+        ));
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let path = Path::new("server.toml");
+
+        assert!(matches!(
+            ConfigLayer::from_toml(path, "prot = 7000"),
+            Err(ConfigError::Parse { .. })
+        ));
+        assert!(matches!(
+            ConfigLayer::from_toml(path, "max-ttl = \"forever\""),
+            Err(ConfigError::Parse { .. })
+        ));
+        assert!(matches!(
+            vars(&[("CL_STORAGE", "s3")]),
+            Err(ConfigError::Invalid {
+                key: "CL_STORAGE",
+                ..
+            })
+        ));
+        assert!(matches!(
+            vars(&[("CL_ADDR", "127.0.0.1,localhost")]),
+            Err(ConfigError::Invalid { key: "CL_ADDR", .. })
+        ));
+
+        let layer = ConfigLayer::from_toml(path, "history-depth = 0").unwrap();
+        assert!(matches!(
+            ServerConfig::resolve(layer),
+            Err(ConfigError::Invalid {
+                key: "history-depth",
+                ..
+            })
+        ));
+
+        let layer =
+            ConfigLayer::from_toml(path, "[key-history-depth]\n\"SHA256:abc\" = 0").unwrap();
+        assert!(matches!(
+            ServerConfig::resolve(layer),
+            Err(ConfigError::Invalid {
+                key: "key-history-depth",
+                ..
+            })
+        ));
+
+        let layer = ConfigLayer::from_toml(path, "log = \"cliplink_server=loud\"").unwrap();
+        assert!(matches!(
+            ServerConfig::resolve(layer),
+            Err(ConfigError::Invalid { key: "log", .. })
+        ));
+    }
+
+    #[test]
+    fn load_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        std::fs::write(
+            &path,
+            "port = 7000\nmax-connections = 4\nhistory-depth = 3\n",
+        )
+        .unwrap();
+        let env = vars(&[("CL_PORT", "7002"), ("CL_MAX_CONNECTIONS", "8")]).unwrap();
+        let flags = ConfigLayer {
+            port: Some(7001),
+            ..Default::default()
+        };
+
+        let config = ServerConfig::load_with_env(Some(&path), flags, env).unwrap();
+
+        assert_eq!(config.port, 7001);
+        assert_eq!(config.max_connections, 8);
+        assert_eq!(config.history_depth, 3);
+        assert_eq!(config.files, [path]);
+        assert_eq!(
+            config.authorized_keys,
+            Config::system_file_path("authorized_keys")
+        );
+        assert_eq!(config.host_key, Config::system_file_path("host_key"));
+        assert!(matches!(config.storage, Storage::Memory));
+
+        assert!(matches!(
+            ServerConfig::load_with_env(
+                Some(&dir.path().join("missing.toml")),
+                ConfigLayer::default(),
+                ConfigLayer::default(),
+            ),
+            Err(ConfigError::Read { .. })
+        ));
+    }
+}
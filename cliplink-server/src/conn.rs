@@ -1,47 +1,83 @@
-use std::{
-    io::{Read, Write},
-    marker::PhantomData,
-    net::TcpStream,
+use std::{marker::PhantomData, sync::Arc, time::Duration};
+
+use cliplink_common::{
+    CodecError, Decoder, Encoder, Frame, FrameError, read_frame, read_frame_async, write_frame,
+    write_frame_async,
+};
+use cliplink_crypto::{
+    CHALLENGE_SIZE, Direction, KexMode, PrivKey, PubKey, RsaPadding, ServerKex, SessionKeys,
+    Transcript, challenge, sequence_nonce,
 };
 
-use cliplink_common::{PACKET_SIZE, Packet, PacketError};
-use cliplink_crypto::{Aes256, GCM_AUTHENTICATION_TAG_SIZE, NONCE_SIZE, RsaPubKey};
+use tokio::net::TcpStream;
+
+use crate::authorized_keys::{AuthorizedKey, AuthorizedKeys};
 
 pub enum Input<'a> {
-    SshHandshake(&'a [u8]),
+    SshHandshake {
+        mode: KexMode,
+        padding: RsaPadding,
+        pub_key: &'a [u8],
+        eph_pub_key: &'a [u8],
+    },
+    Auth(&'a [u8]),
 }
 
 pub enum Output {
-    SshHandshakeAck(Vec<u8>),
+    SshHandshakeAck {
+        reply: Vec<u8>,
+        challenge: [u8; CHALLENGE_SIZE],
+        host_key: String,
+        host_signature: Vec<u8>,
+    },
     SshHandshakeDeny(&'static str),
+    AuthAck,
 }
 
-impl<'a> TryFrom<&'a Packet> for Input<'a> {
-    type Error = PacketError;
-
-    fn try_from(packet: &'a Packet) -> Result<Self, Self::Error> {
-        match packet.ty()? {
-            b"sshsyn" => Ok(Self::SshHandshake(packet.payload()?)),
-            _ => unimplemented!("unexpected type"),
+impl<'a> TryFrom<&'a Frame> for Input<'a> {
+    type Error = ConnectionError;
+
+    fn try_from(frame: &'a Frame) -> Result<Self, Self::Error> {
+        match frame.ty.as_slice() {
+            b"sshsyn" => {
+                let mut decoder = Decoder::new(&frame.payload);
+                let input = Self::SshHandshake {
+                    mode: KexMode::try_from(decoder.get_u8()?)?,
+                    padding: RsaPadding::try_from(decoder.get_u8()?)?,
+                    pub_key: decoder.get_bytes()?,
+                    eph_pub_key: decoder.get_bytes()?,
+                };
+                decoder.finish()?;
+
+                Ok(input)
+            }
+            b"sshauth" => Ok(Self::Auth(&frame.payload)),
+            ty => Err(ConnectionError::UnexpectedType(
+                String::from_utf8_lossy(ty).to_string(),
+            )),
         }
     }
 }
 
-//impl From<Output> for OwnedPacket {
-//    fn from(pl: Output) -> Self {
-//        match pl {
-//            Output::SshHandshakeAck(pl) => OwnedPacket::new(b"sshsynack".fill(0x0), pl),
-//            Output::SshHandshakeDeny(pl) => OwnedPacket::new("sshsyndeny".into(), pl.into()),
-//            _ => unimplemented!("unexpected type"),
-//        }
-//    }
-//}
-
-impl<'a> From<&'a Output> for Packet {
+impl<'a> From<&'a Output> for Frame {
     fn from(pl: &'a Output) -> Self {
         match pl {
-            Output::SshHandshakeAck(pl) => Packet::new(b"sshsynack", pl),
-            Output::SshHandshakeDeny(pl) => Packet::new(b"sshsyndeny", pl.as_bytes()),
+            Output::SshHandshakeAck {
+                reply,
+                challenge,
+                host_key,
+                host_signature,
+            } => Frame::new(
+                b"sshsynack",
+                &Encoder::default()
+                    .put_bytes(reply)
+                    .put_bytes(challenge)
+                    .put_str(host_key)
+                    .put_bytes(host_signature)
+                    .finish(),
+            ),
+            Output::SshHandshakeDeny(pl) => Frame::new(b"sshsyndeny", pl.as_bytes()),
+            Output::AuthAck => Frame::new(b"sshauthack", &[]),
         }
     }
 }
@@ -51,6 +87,27 @@ pub enum ConnectionError {
     #[error("unsupported key type")]
     UnsupportedKeyType,
 
+    #[error("{0}")]
+    Unauthorized(&'static str),
+
+    #[error("unexpected type {0:?}")]
+    UnexpectedType(String),
+
+    #[error("malformed secure frame")]
+    MalformedSecureFrame,
+
+    #[error("out of sequence secure frame: expected {expected}, got {actual}")]
+    OutOfSequence { expected: u64, actual: u64 },
+
+    #[error("secure frame {0} failed to decrypt, it was replayed or altered")]
+    ReplayedOrAltered(u64),
+
+    #[error("authentication failed")]
+    AuthenticationFailed,
+
+    #[error("timed out after {0:?}")]
+    TimedOut(Duration),
+
     #[error(transparent)]
     Aes(#[from] cliplink_crypto::AesError),
 
@@ -58,147 +115,405 @@ pub enum ConnectionError {
     IOError(#[from] std::io::Error),
 
     #[error(transparent)]
-    PacketError(#[from] PacketError),
+    CodecError(#[from] CodecError),
+
+    #[error(transparent)]
+    FrameError(#[from] FrameError),
 
     #[error(transparent)]
     RsaError(#[from] cliplink_crypto::RsaError),
 }
 
+pub(crate) const SECURE_FRAME_TYPE: &[u8] = b"sec";
+
+/// Runs CPU-bound key operations off the async workers, like [`crate::session::Session`] does for
+/// repository calls.
+async fn blocking<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let span = tracing::Span::current();
+
+    tokio::task::spawn_blocking(move || span.in_scope(f))
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+/// Deny reason for any malformed or failed handshake, so peers can't tell failures apart.
+const HANDSHAKE_FAILED: &str = "handshake failed";
+
 pub struct Handshake;
 pub struct HandshakeAck;
+pub struct Auth;
 pub struct Secure;
 
 pub struct Connection<State> {
-    aes_key: Option<Aes256>,
-    rsa_pub_key: Option<RsaPubKey>,
+    session_keys: Option<SessionKeys>,
+    kex: Option<(KexMode, RsaPadding, Vec<u8>)>,
+    transcript: Transcript,
+    send_seq: u64,
+    recv_seq: u64,
+    pub_key: Option<PubKey>,
+    authorized_key: Option<AuthorizedKey>,
+    host_key: Arc<PrivKey>,
     phantom: PhantomData<State>,
     stream: TcpStream,
+    timeout: Duration,
 }
 
 impl<T> Connection<T> {
     fn mutate<N>(self) -> Connection<N> {
         Connection {
-            aes_key: self.aes_key,
-            rsa_pub_key: self.rsa_pub_key,
+            session_keys: self.session_keys,
+            kex: self.kex,
+            transcript: self.transcript,
+            send_seq: self.send_seq,
+            recv_seq: self.recv_seq,
+            pub_key: self.pub_key,
+            authorized_key: self.authorized_key,
+            host_key: self.host_key,
             phantom: PhantomData::<N>,
             stream: self.stream,
+            timeout: self.timeout,
         }
     }
 
-    pub fn read_bytes(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-        let buf_len = match self.stream.read(buf) {
-            Ok(buf_len) => buf_len,
-            Err(err) => {
-                eprintln!("read failure, closing socket: {err:?}");
-                self.stream
-                    .shutdown(std::net::Shutdown::Both)
-                    .expect("failed to shutdown");
-
-                panic!("{err}");
-            }
-        };
-
-        buf[buf_len..].fill(0x0);
-
-        dbg!("read", buf.len(), buf_len);
-        Ok(buf_len)
+    /// Reads one frame, giving up after the connection timeout.
+    pub async fn read_frame(&mut self) -> Result<Frame, ConnectionError> {
+        self.read_frame_within(self.timeout).await
     }
 
-    fn write_bytes(&mut self, buf: &[u8]) -> Result<usize, ConnectionError> {
-        let buf_len = self.stream.write(&buf)?;
+    async fn read_frame_within(&mut self, timeout: Duration) -> Result<Frame, ConnectionError> {
+        tokio::time::timeout(timeout, read_frame_async(&mut self.stream))
+            .await
+            .map_err(|_| ConnectionError::TimedOut(timeout))?
+            .map_err(ConnectionError::from)
+    }
 
-        dbg!("write", buf.len(), buf_len);
-        Ok(buf_len)
+    async fn write_frame(&mut self, frame: &Frame) -> Result<(), ConnectionError> {
+        tokio::time::timeout(self.timeout, write_frame_async(&mut self.stream, frame))
+            .await
+            .map_err(|_| ConnectionError::TimedOut(self.timeout))?
+            .map_err(ConnectionError::from)
     }
 
-    fn write_output(&mut self, output: Output) -> Result<usize, ConnectionError> {
-        self.write_bytes(Packet::from(&output).as_bytes())
+    async fn write_output(&mut self, output: Output) -> Result<(), ConnectionError> {
+        self.write_frame(&Frame::from(&output)).await
     }
 }
 
 // sshsyn > sshsynack | sshsyndeny
 //
-// client                  | server
-// pubkeysyn (pub ssh key) > pubkeyack
-// enckeyack               < enckey (encrypted)
-// copy   (payload)        > copyack
-// paste                   < pasteack (payload)
+// client                                  | server
+// sshsyn (kex mode, rsa padding,          > sshsynack (eph key, sealed secret, challenge,
+//         pub ssh key, eph key)           |            host key, host transcript signature)
+// sshauth (transcript signature)          > sshauthack | sshsyndeny
 impl Connection<Handshake> {
-    pub fn from(stream: TcpStream) -> Self {
+    /// Wraps an accepted stream. Every frame read or write must complete within `timeout`.
+    pub fn from(stream: TcpStream, host_key: Arc<PrivKey>, timeout: Duration) -> Self {
         Self {
-            aes_key: None,
-            rsa_pub_key: None,
+            session_keys: None,
+            kex: None,
+            transcript: Transcript::default(),
+            send_seq: 0,
+            recv_seq: 0,
+            pub_key: None,
+            authorized_key: None,
+            host_key,
             phantom: PhantomData::<Handshake>,
             stream,
+            timeout,
         }
     }
 
-    pub fn validate_ssh_key(
+    pub async fn validate_ssh_key(
         mut self,
-        packet: &Packet,
+        frame: &Frame,
+        authorized_keys: &Arc<AuthorizedKeys>,
     ) -> Result<Connection<HandshakeAck>, ConnectionError> {
-        let Input::SshHandshake(pub_key) = Input::try_from(packet)? else {
-            self.write_output(Output::SshHandshakeDeny("unsupported key type"))?;
+        let (mode, padding, pub_key, eph_pub_key) = match Input::try_from(frame) {
+            Ok(Input::SshHandshake {
+                mode,
+                padding,
+                pub_key,
+                eph_pub_key,
+            }) => (mode, padding, pub_key, eph_pub_key),
+            input => {
+                self.write_output(Output::SshHandshakeDeny(HANDSHAKE_FAILED))
+                    .await?;
+
+                return Err(input.err().unwrap_or(ConnectionError::UnexpectedType(
+                    String::from_utf8_lossy(&frame.ty).to_string(),
+                )));
+            }
+        };
+
+        let Ok(pub_key) = PubKey::from_openssh(pub_key) else {
+            self.write_output(Output::SshHandshakeDeny(HANDSHAKE_FAILED))
+                .await?;
 
             return Err(ConnectionError::UnsupportedKeyType);
         };
 
-        self.rsa_pub_key = Some(RsaPubKey::from_openssh(pub_key)?);
+        let peer = self.stream.peer_addr()?.ip();
+        let authorized_keys = authorized_keys.clone();
+        let (pub_key, authorized) = blocking(move || {
+            let authorized = authorized_keys.authorize(&pub_key, peer);
+            (pub_key, authorized)
+        })
+        .await;
+        let authorized_key = match authorized {
+            Ok(authorized_key) => authorized_key,
+            Err(denied) => {
+                self.write_output(Output::SshHandshakeDeny(denied.reason()))
+                    .await?;
+
+                return Err(ConnectionError::Unauthorized(denied.reason()));
+            }
+        };
+
+        self.pub_key = Some(pub_key);
+        self.authorized_key = Some(authorized_key);
+        self.kex = Some((mode, padding, eph_pub_key.to_vec()));
+        self.transcript.update(&frame.payload);
 
         Ok(self.mutate::<HandshakeAck>())
     }
 }
 
 impl Connection<HandshakeAck> {
-    pub fn gen_aes256_key(mut self) -> Result<Connection<Secure>, ConnectionError> {
-        let rsa_pub_key = self.rsa_pub_key.as_ref().expect("no rsa key available");
+    pub async fn gen_session_keys(mut self) -> Result<Connection<Auth>, ConnectionError> {
+        let pub_key = self.pub_key.take().expect("no ssh key available");
+        let (mode, padding, eph_pub_key) = self.kex.take().expect("no key exchange available");
+
+        let (pub_key, kex) = blocking(move || {
+            let kex = ServerKex::respond(mode, padding, &pub_key, &eph_pub_key);
+            (pub_key, kex)
+        })
+        .await;
+        self.pub_key = Some(pub_key);
+
+        let (reply, session_keys) = match kex {
+            Ok(kex) => kex,
+            Err(err) => {
+                self.write_output(Output::SshHandshakeDeny(HANDSHAKE_FAILED))
+                    .await?;
 
-        let aes_key = Aes256::new()?;
-        let aes_key_enc_buf = rsa_pub_key.encrypt_pkcs1v15(aes_key.as_bytes())?;
+                return Err(err.into());
+            }
+        };
+        let challenge = challenge();
+        let host_key = self.host_key.pub_key().to_openssh(None)?;
+
+        // The host signature covers everything the client has seen so far, so a relay can't
+        // splice a genuine server signature into another handshake.
+        let mut transcript = self.transcript.clone();
+        transcript.update(&reply);
+        transcript.update(&challenge);
+        transcript.update(host_key.as_bytes());
+        let signer = self.host_key.clone();
+        let host_signature = blocking(move || signer.sign(&transcript.host_message())).await;
+
+        let frame = Frame::from(&Output::SshHandshakeAck {
+            reply,
+            challenge,
+            host_key,
+            host_signature,
+        });
+        self.transcript.update(&frame.payload);
+        self.write_frame(&frame).await?;
+
+        self.session_keys = Some(session_keys);
+
+        Ok(self.mutate::<Auth>())
+    }
+}
 
-        self.write_output(Output::SshHandshakeAck(aes_key_enc_buf))?;
+impl Connection<Auth> {
+    /// Checks the client signature over the handshake transcript before trusting its key.
+    pub async fn verify_auth(
+        mut self,
+        frame: &Frame,
+    ) -> Result<Connection<Secure>, ConnectionError> {
+        let pub_key = self.pub_key.take().expect("no ssh key available");
+        let auth_message = self.transcript.auth_message();
+        let signature = match Input::try_from(frame) {
+            Ok(Input::Auth(signature)) => Some(signature.to_vec()),
+            _ => None,
+        };
+
+        let (pub_key, verified) = blocking(move || {
+            let verified = signature
+                .is_some_and(|signature| pub_key.verify(&auth_message, &signature).is_ok());
+            (pub_key, verified)
+        })
+        .await;
+        self.pub_key = Some(pub_key);
 
-        self.aes_key = Some(aes_key);
+        if !verified {
+            self.write_output(Output::SshHandshakeDeny("signature verification failed"))
+                .await?;
+
+            return Err(ConnectionError::AuthenticationFailed);
+        }
+
+        self.write_output(Output::AuthAck).await?;
 
         Ok(self.mutate::<Secure>())
     }
 }
 
 impl Connection<Secure> {
+    pub fn authorized_key(&self) -> &AuthorizedKey {
+        self.authorized_key
+            .as_ref()
+            .expect("no authorized key available")
+    }
+
     pub fn id(&self) -> Result<String, ConnectionError> {
         Ok(self
-            .rsa_pub_key
+            .pub_key
             .as_ref()
-            .expect("no rsa key available")
+            .expect("no ssh key available")
             .to_openssh(None)?)
     }
 
-    pub fn read_packet_sec(&mut self) -> Result<Packet, ConnectionError> {
-        let mut buf = [0u8; NONCE_SIZE + PACKET_SIZE + GCM_AUTHENTICATION_TAG_SIZE];
-        let _ = self.read_bytes(&mut buf)?;
+    /// SHA256 fingerprint of the authenticated key, as printed by `ssh-keygen -l`.
+    pub fn fingerprint(&self) -> Result<String, ConnectionError> {
+        Ok(self
+            .pub_key
+            .as_ref()
+            .expect("no ssh key available")
+            .fingerprint()?)
+    }
 
-        let mut nonce = [0u8; NONCE_SIZE];
-        nonce.copy_from_slice(&buf[0..NONCE_SIZE]);
+    /// Reads one encrypted frame from the stream and decodes the `Frame` sealed within it.
+    ///
+    /// Secure frames carry their sequence number as `request_id` and the ciphertext of the wire
+    /// encoding of the inner frame as payload. Each direction counts from zero and the nonce is
+    /// derived from the expected sequence number, so replayed, dropped or reordered frames are
+    /// rejected.
+    pub async fn read_packet_sec(&mut self) -> Result<Frame, ConnectionError> {
+        self.read_packet_sec_within(self.timeout).await
+    }
 
-        let aes_key = self.aes_key.as_ref().expect("no aes key available");
-        let mut dec_buf = [0u8; PACKET_SIZE];
-        dec_buf.copy_from_slice(&aes_key.decrypt(nonce, &buf[NONCE_SIZE..])?);
+    /// Like [`Self::read_packet_sec`], but waits up to `timeout` for the frame, e.g. while the
+    /// session is idle between requests.
+    pub async fn read_packet_sec_within(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Frame, ConnectionError> {
+        let frame = self.read_frame_within(timeout).await?;
 
-        Ok(Packet::from_bytes(&dec_buf))
+        if frame.ty != SECURE_FRAME_TYPE {
+            return Err(ConnectionError::MalformedSecureFrame);
+        }
+
+        if frame.request_id != self.recv_seq {
+            return Err(ConnectionError::OutOfSequence {
+                expected: self.recv_seq,
+                actual: frame.request_id,
+            });
+        }
+
+        let session_keys = self
+            .session_keys
+            .as_ref()
+            .expect("no session keys available");
+        // A frame replayed under the expected sequence number was sealed under another nonce.
+        let dec_buf = session_keys
+            .client
+            .decrypt(
+                sequence_nonce(Direction::ClientToServer, self.recv_seq),
+                &frame.payload,
+            )
+            .map_err(|_| ConnectionError::ReplayedOrAltered(self.recv_seq))?;
+        self.recv_seq += 1;
+
+        Ok(read_frame(&mut dec_buf.as_slice())?)
+    }
+
+    pub async fn write_packet_sec(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let session_keys = self
+            .session_keys
+            .as_ref()
+            .expect("no session keys available");
+
+        let mut plain_buf = Vec::new();
+        write_frame(&mut plain_buf, &frame)?;
+
+        let enc_buf = session_keys.server.encrypt_with_nonce(
+            sequence_nonce(Direction::ServerToClient, self.send_seq),
+            &plain_buf,
+        )?;
+
+        let mut frame = Frame::new(SECURE_FRAME_TYPE, &enc_buf);
+        frame.request_id = self.send_seq;
+        self.send_seq += 1;
+
+        self.write_frame(&frame).await
     }
+}
+
+#[cfg(test)]
+mod test {
+    use cliplink_crypto::{AES_256_SIZE, Aes256, Ed25519PrivKey};
+    use tokio::net::TcpListener;
 
-    pub fn write_packet_sec(&mut self, packet: Packet) -> Result<usize, ConnectionError> {
-        let aes_key = self.aes_key.as_ref().expect("no aes key available");
+    use super::*;
 
-        let (nonce, enc_buf) = aes_key.encrypt(packet.as_bytes())?;
-        dbg!(nonce.len(), enc_buf.len());
+    /// Seals `frame` as the client does for sequence number `seq`.
+    fn sealed(key: &Aes256, seq: u64, frame: &Frame) -> Frame {
+        let mut plain_buf = Vec::new();
+        write_frame(&mut plain_buf, frame).unwrap();
+        let enc_buf = key
+            .encrypt_with_nonce(sequence_nonce(Direction::ClientToServer, seq), &plain_buf)
+            .unwrap();
 
-        let mut inline_buf = Vec::with_capacity(nonce.len() + enc_buf.len());
-        inline_buf.extend_from_slice(&nonce);
-        inline_buf.extend_from_slice(&enc_buf);
+        let mut frame = Frame::new(SECURE_FRAME_TYPE, &enc_buf);
+        frame.request_id = seq;
+        frame
+    }
 
-        let buf_len = self.write_bytes(&inline_buf)?;
+    #[tokio::test]
+    async fn rejects_replayed_frames() {
+        let key = [7u8; AES_256_SIZE];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let host_key = Arc::new(PrivKey::Ed25519(Ed25519PrivKey::generate()));
+        let mut conn =
+            Connection::from(stream, host_key, Duration::from_secs(5)).mutate::<Secure>();
+        conn.session_keys = Some(SessionKeys {
+            client: Aes256::try_from(key).unwrap(),
+            server: Aes256::try_from(key).unwrap(),
+        });
+
+        let client = Aes256::try_from(key).unwrap();
+        let first = sealed(&client, 0, &Frame::new(b"first", &[]));
+        let mut rewritten = first.clone();
+        rewritten.request_id = 1;
+        let second = sealed(&client, 1, &Frame::new(b"second", &[]));
+        for frame in [&first, &first, &rewritten, &second] {
+            write_frame_async(&mut peer, frame).await.unwrap();
+        }
 
-        Ok(buf_len)
+        assert_eq!(conn.read_packet_sec().await.unwrap().ty, b"first");
+        assert!(matches!(
+            conn.read_packet_sec().await,
+            Err(ConnectionError::OutOfSequence {
+                expected: 1,
+                actual: 0
+            })
+        ));
+        assert!(matches!(
+            conn.read_packet_sec().await,
+            Err(ConnectionError::ReplayedOrAltered(1))
+        ));
+        assert_eq!(conn.read_packet_sec().await.unwrap().ty, b"second");
     }
 }
@@ -0,0 +1,735 @@
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::UNIX_EPOCH,
+};
+
+use cliplink_common::{CodecError, DEFAULT_CLIP, Decoder, Encoder, HistoryEntry};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::repository::{Claim, ClipInfo, Paste, Repository, RepositoryError, unix_time};
+
+/// Marks the start of an entry file, followed by the u32 length-prefixed [`Meta`] and then
+/// the payload. Fields added to [`Meta`] go at its end and are optional when decoding, so older
+/// entries stay readable.
+const ENTRY_MAGIC: &[u8; 4] = b"CLE1";
+
+/// Version of the on-disk layout, recorded in [`VERSION_FILE`] once [`FileRepository::upgrade`]
+/// brought the data directory up to it. Bump it along with any new upgrade step.
+const LAYOUT_VERSION: u32 = 1;
+
+/// Holds the [`LAYOUT_VERSION`] of the data directory, in decimal.
+const VERSION_FILE: &str = ".version";
+
+/// Largest [`Meta`] length accepted from an entry header, well above what [`Meta::header`]
+/// writes, so a corrupt length can't make a read allocate gigabytes.
+const MAX_META_LEN: u32 = 4096;
+
+/// Stores each clip as a directory of numbered entries: `<data dir>/<sha256(id)>/<clip>/<seq>`,
+/// the highest `seq` being the latest paste.
+///
+/// Writes go to a temporary file in the clip directory, which is synced and hard linked to the
+/// next free `seq`. Linking fails instead of overwriting, so readers never see a torn entry and
+/// concurrent writers to the same clip each get their own `seq`. A write that loses its clip
+/// directory to a concurrent delete starts over in a new one.
+///
+/// Claims on burn-after-read entries are kept in memory, as the entry path: they only last as
+/// long as the server, so the entries claimed by a server that went away become available again.
+/// New entries never take a `seq` at or below a claimed one, even once it was trimmed, purged or
+/// deleted, so a claim never settles on an entry pasted after it was taken.
+#[derive(Debug)]
+pub struct FileRepository(PathBuf, Mutex<HashSet<PathBuf>>);
+
+/// Entry metadata, stored ahead of the payload.
+#[derive(Debug)]
+struct Meta {
+    created_at: u64,
+    expires_at: Option<u64>,
+    once: bool,
+    content_type: Option<String>,
+    filename: Option<String>,
+    e2e: bool,
+}
+
+impl Meta {
+    fn header(&self) -> Vec<u8> {
+        let meta = Encoder::default()
+            .put_u64(self.created_at)
+            .put_u64(self.expires_at.unwrap_or(0))
+            .put_u8(self.once as u8)
+            .put_str(self.content_type.as_deref().unwrap_or_default())
+            .put_str(self.filename.as_deref().unwrap_or_default())
+            .put_u8(self.e2e as u8)
+            .finish();
+
+        let mut header = ENTRY_MAGIC.to_vec();
+        header.extend_from_slice(&Encoder::default().put_bytes(&meta).finish());
+        header
+    }
+
+    /// Reads the metadata at the start of `reader`, returning it along with the header length.
+    fn read(mut reader: impl Read) -> std::io::Result<(Self, u64)> {
+        let mut prefix = [0u8; 8];
+        reader.read_exact(&mut prefix)?;
+        if prefix[..4] != ENTRY_MAGIC[..] {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "not a clip entry",
+            ));
+        }
+
+        let len = Decoder::new(&prefix[4..]).get_u32().map_err(invalid_data)?;
+        if len > MAX_META_LEN {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("clip entry metadata of {len} bytes is too large"),
+            ));
+        }
+
+        let mut meta = vec![0u8; len as usize];
+        reader.read_exact(&mut meta)?;
+
+        Ok((
+            Self::decode(&meta).map_err(invalid_data)?,
+            prefix.len() as u64 + len as u64,
+        ))
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, CodecError> {
+        let mut decoder = Decoder::new(buf);
+        let created_at = decoder.get_u64()?;
+        let expires_at = decoder.get_u64()?;
+        let once = !decoder.is_empty() && decoder.get_u8()? != 0;
+        let mut optional_str = || match decoder.is_empty() {
+            true => Ok(None),
+            false => decoder.get_str().map(|value| {
+                Some(value)
+                    .filter(|value| !value.is_empty())
+                    .map(str::to_string)
+            }),
+        };
+        let content_type = optional_str()?;
+        let filename = optional_str()?;
+        let e2e = !decoder.is_empty() && decoder.get_u8()? != 0;
+        decoder.finish()?;
+
+        Ok(Self {
+            created_at,
+            expires_at: (expires_at != 0).then_some(expires_at),
+            once,
+            content_type,
+            filename,
+            e2e,
+        })
+    }
+
+    /// Metadata for an entry written before entries had any: created when last modified, never
+    /// expiring.
+    fn legacy(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            created_at: std::fs::metadata(path)?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or_default(),
+            expires_at: None,
+            once: false,
+            content_type: None,
+            filename: None,
+            e2e: false,
+        })
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+fn invalid_data(err: CodecError) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, err)
+}
+
+impl FileRepository {
+    pub fn new(dir: PathBuf) -> Result<Self, RepositoryError> {
+        std::fs::create_dir_all(&dir)?;
+
+        let repo = Self(dir, Mutex::default());
+        repo.upgrade()?;
+
+        Ok(repo)
+    }
+
+    /// Brings clips written by older versions up to the current layout: clips stored as a single
+    /// `<sha256(id)>/<clip>` file become the first entry of their clip directory, and entries
+    /// without a [`Meta`] header get one.
+    ///
+    /// Skipped once the data directory records [`LAYOUT_VERSION`], so clips aren't all scanned
+    /// on every start.
+    fn upgrade(&self) -> Result<(), RepositoryError> {
+        let version_path = self.0.join(VERSION_FILE);
+        let version = match std::fs::read_to_string(&version_path) {
+            Ok(version) => version.trim().parse().map_err(|_| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid layout version {version:?} in {version_path:?}"),
+                )
+            })?,
+            Err(err) if err.kind() == ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+        if version >= LAYOUT_VERSION {
+            return Ok(());
+        }
+
+        for id_dir in self.id_dirs()? {
+            for clip in std::fs::read_dir(&id_dir)? {
+                let clip = clip?;
+                let name = clip.file_name();
+                let Some(name) = name.to_str() else { continue };
+                if name.starts_with('.') || !clip.file_type()?.is_file() {
+                    continue;
+                }
+
+                let legacy_path = temp_path(&id_dir, name);
+                std::fs::rename(clip.path(), &legacy_path)?;
+                std::fs::create_dir(clip.path())?;
+
+                let payload = std::fs::read(&legacy_path)?;
+                write_entry(
+                    &clip.path(),
+                    name,
+                    &Meta::legacy(&legacy_path)?,
+                    &payload,
+                    1,
+                    0,
+                )?;
+
+                std::fs::remove_file(legacy_path)?;
+            }
+
+            for (name, clip_dir) in clip_dirs(&id_dir)? {
+                for (_, path) in entries(&clip_dir)? {
+                    add_header(&clip_dir, &name, &path)?;
+                }
+            }
+        }
+
+        std::fs::write(version_path, format!("{LAYOUT_VERSION}\n"))?;
+
+        Ok(())
+    }
+
+    fn id_dirs(&self) -> Result<Vec<PathBuf>, RepositoryError> {
+        let mut id_dirs = Vec::new();
+        for id_dir in std::fs::read_dir(&self.0)? {
+            let id_dir = id_dir?;
+            if id_dir.file_type()?.is_dir() {
+                id_dirs.push(id_dir.path());
+            }
+        }
+
+        Ok(id_dirs)
+    }
+
+    fn id_dir(&self, id: &str) -> PathBuf {
+        let digest = Sha256::digest(id.as_bytes());
+        let name: String = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+
+        self.0.join(name)
+    }
+
+    fn clip_dir(&self, id: &str, clip: Option<&str>) -> PathBuf {
+        self.id_dir(id).join(clip.unwrap_or(DEFAULT_CLIP))
+    }
+
+    fn claims(&self) -> std::sync::MutexGuard<'_, HashSet<PathBuf>> {
+        self.1.lock().expect("claims lock poisoned")
+    }
+
+    /// Lowest `seq` a new entry in `dir` may take: above every claimed entry in it.
+    fn min_seq(&self, dir: &Path) -> u64 {
+        self.claims()
+            .iter()
+            .filter(|path| path.parent() == Some(dir))
+            .filter_map(|path| path.file_name()?.to_str()?.parse::<u64>().ok())
+            .map(|seq| seq + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn claim(&self, id: &str, clip: &str, seq: u64, path: &Path) -> Result<Claim, RepositoryError> {
+        let mut claims = self.claims();
+
+        // A committed claim deletes its entry before letting go of it.
+        if claims.contains(path) || !path.exists() {
+            return Err(RepositoryError::NotFound);
+        }
+        claims.insert(path.to_path_buf());
+
+        Ok(Claim {
+            id: id.to_string(),
+            clip: clip.to_string(),
+            entry: seq,
+        })
+    }
+
+    fn claim_path(&self, claim: &Claim) -> PathBuf {
+        self.clip_dir(&claim.id, Some(&claim.clip))
+            .join(entry_name(claim.entry))
+    }
+}
+
+fn entry_name(seq: u64) -> String {
+    format!("{seq:020}")
+}
+
+/// Lists the clip directories in `id_dir` as `(clip, path)`, skipping temporary files.
+fn clip_dirs(id_dir: &Path) -> Result<Vec<(String, PathBuf)>, RepositoryError> {
+    let dir = match std::fs::read_dir(id_dir) {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut clip_dirs = Vec::new();
+    for clip_dir in dir {
+        let clip_dir = clip_dir?;
+        let Ok(name) = clip_dir.file_name().into_string() else {
+            continue;
+        };
+
+        if !name.starts_with('.') && clip_dir.file_type()?.is_dir() {
+            clip_dirs.push((name, clip_dir.path()));
+        }
+    }
+
+    Ok(clip_dirs)
+}
+
+/// Lists the entries of `clip_dir` as `(seq, path)`, latest first.
+fn entries(clip_dir: &Path) -> Result<Vec<(u64, PathBuf)>, RepositoryError> {
+    let dir = match std::fs::read_dir(clip_dir) {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut entries = Vec::new();
+    for entry in dir {
+        let entry = entry?;
+        if let Some(seq) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u64>().ok())
+        {
+            entries.push((seq, entry.path()));
+        }
+    }
+    entries.sort_unstable_by(|(a, _), (b, _)| b.cmp(a));
+
+    Ok(entries)
+}
+
+/// Writes `meta` and `payload` to a new file at `path`, synced before returning.
+fn create_entry_file(path: &Path, meta: &Meta, payload: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    file.write_all(&meta.header())?;
+    file.write_all(payload)?;
+    file.sync_all()
+}
+
+/// Rewrites an entry written before entries had a [`Meta`] header, leaving current ones be.
+fn add_header(dir: &Path, clip: &str, path: &Path) -> Result<(), RepositoryError> {
+    let mut magic = [0u8; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) if magic == *ENTRY_MAGIC => return Ok(()),
+        Err(err) if err.kind() != ErrorKind::UnexpectedEof => return Err(err.into()),
+        _ => {}
+    }
+
+    let temp_path = temp_path(dir, clip);
+    let result = (|| -> Result<(), RepositoryError> {
+        let payload = std::fs::read(path)?;
+        create_entry_file(&temp_path, &Meta::legacy(path)?, &payload)?;
+        Ok(std::fs::rename(&temp_path, path)?)
+    })();
+
+    let _ = std::fs::remove_file(&temp_path);
+
+    result
+}
+
+/// Links a new entry into `dir` as the latest, dropping all but the `depth` most recent. The
+/// entry takes `min_seq` or above.
+fn write_entry(
+    dir: &Path,
+    clip: &str,
+    meta: &Meta,
+    payload: &[u8],
+    depth: usize,
+    min_seq: u64,
+) -> Result<(), RepositoryError> {
+    let temp_path = temp_path(dir, clip);
+
+    let result = (|| -> Result<(), RepositoryError> {
+        create_entry_file(&temp_path, meta, payload)?;
+
+        let entries = loop {
+            let entries = entries(dir)?;
+            let seq = entries.first().map_or(0, |(seq, _)| seq + 1).max(min_seq);
+
+            match std::fs::hard_link(&temp_path, dir.join(entry_name(seq))) {
+                Ok(()) => break entries,
+                // Another writer took this seq first.
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err.into()),
+            }
+        };
+
+        // `entries` was listed before the new one was linked.
+        for (_, path) in entries.iter().skip(depth.saturating_sub(1)) {
+            remove_entry(path)?;
+        }
+
+        // Persist the new entry itself.
+        #[cfg(unix)]
+        File::open(dir)?.sync_all()?;
+
+        Ok(())
+    })();
+
+    let _ = std::fs::remove_file(&temp_path);
+
+    result
+}
+
+/// Opens an entry and reads its metadata, returning the file positioned at the payload along
+/// with the payload size, or `None` if the entry was removed meanwhile.
+fn open_entry(path: &Path) -> Result<Option<(File, Meta, u64)>, RepositoryError> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let len = file.metadata()?.len();
+    let (meta, header_len) = Meta::read(&mut file)?;
+
+    Ok(Some((file, meta, len.saturating_sub(header_len))))
+}
+
+/// Removes an entry, which a concurrent writer or the reaper may have removed already.
+fn remove_entry(path: &Path) -> Result<(), RepositoryError> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Temporary files start with a dot, which clip names never do.
+fn temp_path(dir: &Path, clip: &str) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    dir.join(format!(
+        ".{clip}.{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+impl Repository for FileRepository {
+    fn get(
+        &self,
+        id: &str,
+        clip: Option<&str>,
+        index: usize,
+    ) -> Result<(Paste, Option<Claim>), RepositoryError> {
+        let entries = entries(&self.clip_dir(id, clip))?;
+        let (seq, path) = entries.get(index).ok_or(RepositoryError::NotFound)?;
+
+        let (mut file, meta, _) = open_entry(path)?.ok_or(RepositoryError::NotFound)?;
+        if meta.is_expired(unix_time()) {
+            return Err(RepositoryError::Expired);
+        }
+
+        let claim = match meta.once {
+            true => Some(self.claim(id, clip.unwrap_or(DEFAULT_CLIP), *seq, path)?),
+            false => None,
+        };
+
+        let mut payload = Vec::new();
+        if let Err(err) = file.read_to_end(&mut payload) {
+            if let Some(claim) = &claim {
+                self.release(claim)?;
+            }
+            return Err(err.into());
+        }
+
+        let paste = Paste {
+            payload,
+            content_type: meta.content_type,
+            filename: meta.filename,
+            expires_at: meta.expires_at,
+            once: meta.once,
+            e2e: meta.e2e,
+        };
+
+        Ok((paste, claim))
+    }
+
+    fn commit(&self, claim: &Claim) -> Result<(), RepositoryError> {
+        let path = self.claim_path(claim);
+        let mut claims = self.claims();
+
+        if claims.contains(&path) {
+            remove_entry(&path)?;
+            claims.remove(&path);
+        }
+
+        Ok(())
+    }
+
+    fn release(&self, claim: &Claim) -> Result<(), RepositoryError> {
+        self.claims().remove(&self.claim_path(claim));
+
+        Ok(())
+    }
+
+    fn patch(
+        &self,
+        id: &str,
+        clip: Option<&str>,
+        paste: Paste,
+        depth: usize,
+    ) -> Result<(), RepositoryError> {
+        let dir = self.clip_dir(id, clip);
+        let min_seq = self.min_seq(&dir);
+
+        let meta = Meta {
+            created_at: unix_time(),
+            expires_at: paste.expires_at,
+            once: paste.once,
+            content_type: paste.content_type,
+            filename: paste.filename,
+            e2e: paste.e2e,
+        };
+
+        loop {
+            std::fs::create_dir_all(&dir)?;
+
+            match write_entry(
+                &dir,
+                clip.unwrap_or(DEFAULT_CLIP),
+                &meta,
+                &paste.payload,
+                depth,
+                min_seq,
+            ) {
+                // A concurrent `delete` moved the clip directory away midway, so paste into a
+                // fresh one: this paste lands after the delete.
+                Err(RepositoryError::IOError(err)) if err.kind() == ErrorKind::NotFound => {}
+                result => return result,
+            }
+        }
+    }
+
+    fn history(&self, id: &str, clip: Option<&str>) -> Result<Vec<HistoryEntry>, RepositoryError> {
+        let mut history = Vec::new();
+
+        for (_, path) in entries(&self.clip_dir(id, clip))? {
+            let Some((_, meta, size)) = open_entry(&path)? else {
+                continue;
+            };
+
+            history.push(HistoryEntry {
+                size,
+                created_at: meta.created_at,
+                expires_at: meta.expires_at,
+                once: meta.once,
+                e2e: meta.e2e,
+            });
+        }
+
+        Ok(history)
+    }
+
+    fn list(&self, id: &str) -> Result<Vec<ClipInfo>, RepositoryError> {
+        let mut clips = Vec::new();
+
+        for (name, clip_dir) in clip_dirs(&self.id_dir(id))? {
+            let entries = entries(&clip_dir)?;
+            let Some((_, meta, size)) = entries
+                .iter()
+                .map(|(_, path)| open_entry(path))
+                .find_map(Result::transpose)
+                .transpose()?
+            else {
+                continue;
+            };
+
+            clips.push(ClipInfo {
+                name,
+                size,
+                content_type: meta.content_type,
+                updated_at: meta.created_at,
+                entries: entries.len(),
+            });
+        }
+        clips.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(clips)
+    }
+
+    fn delete(&self, id: &str, clip: Option<&str>) -> Result<(), RepositoryError> {
+        let dir = self.clip_dir(id, clip);
+        if entries(&dir)?.is_empty() {
+            return Err(RepositoryError::NotFound);
+        }
+
+        // Move the clip out of sight first, so it disappears at once.
+        let deleted_path = temp_path(&self.id_dir(id), clip.unwrap_or(DEFAULT_CLIP));
+        match std::fs::rename(&dir, &deleted_path) {
+            Ok(()) => Ok(std::fs::remove_dir_all(deleted_path)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Err(RepositoryError::NotFound),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn purge_expired(&self, now: u64) -> Result<usize, RepositoryError> {
+        let mut purged = 0;
+
+        for id_dir in self.id_dirs()? {
+            for (_, clip_dir) in clip_dirs(&id_dir)? {
+                for (_, path) in entries(&clip_dir)? {
+                    let meta = match open_entry(&path) {
+                        Ok(Some((_, meta, _))) => meta,
+                        Ok(None) => continue,
+                        // One bad entry shouldn't keep the others from being purged.
+                        Err(err) => {
+                            warn!("skipping unreadable entry {path:?}: {err}");
+                            continue;
+                        }
+                    };
+
+                    if meta.is_expired(now) {
+                        remove_entry(&path)?;
+                        purged += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repository::test::conformance;
+
+    fn paste(payload: &[u8], expires_at: Option<u64>) -> Paste {
+        Paste {
+            payload: payload.to_vec(),
+            expires_at,
+            ..Paste::default()
+        }
+    }
+
+    #[test]
+    fn conforms() {
+        let dir = tempfile::tempdir().unwrap();
+        conformance(&FileRepository::new(dir.path().to_path_buf()).unwrap());
+    }
+
+    #[test]
+    fn upgrades_single_file_clips() {
+        let dir = tempfile::tempdir().unwrap();
+        let id_dir =
+            FileRepository(dir.path().to_path_buf(), Mutex::default()).id_dir("ssh-ed25519 AAAA");
+        std::fs::create_dir_all(&id_dir).unwrap();
+        std::fs::write(id_dir.join(DEFAULT_CLIP), b"legacy").unwrap();
+
+        let repo = FileRepository::new(dir.path().to_path_buf()).unwrap();
+        assert_eq!(
+            repo.get("ssh-ed25519 AAAA", None, 0).unwrap().0.payload,
+            b"legacy"
+        );
+
+        repo.patch("ssh-ed25519 AAAA", None, paste(b"new", None), 10)
+            .unwrap();
+        assert_eq!(
+            repo.get("ssh-ed25519 AAAA", None, 1).unwrap().0.payload,
+            b"legacy"
+        );
+    }
+
+    #[test]
+    fn upgrades_headerless_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let clip_dir = FileRepository(dir.path().to_path_buf(), Mutex::default())
+            .clip_dir("ssh-ed25519 AAAA", None);
+        std::fs::create_dir_all(&clip_dir).unwrap();
+        std::fs::write(clip_dir.join(entry_name(0)), b"").unwrap();
+        std::fs::write(clip_dir.join(entry_name(1)), b"headerless").unwrap();
+
+        let repo = FileRepository::new(dir.path().to_path_buf()).unwrap();
+        assert_eq!(
+            repo.get("ssh-ed25519 AAAA", None, 0).unwrap().0.payload,
+            b"headerless"
+        );
+        assert_eq!(
+            repo.history("ssh-ed25519 AAAA", None)
+                .unwrap()
+                .iter()
+                .map(|entry| entry.size)
+                .collect::<Vec<_>>(),
+            [10, 0]
+        );
+
+        // Once upgraded, the data directory isn't scanned again.
+        std::fs::write(clip_dir.join(entry_name(2)), b"headerless").unwrap();
+        FileRepository::new(dir.path().to_path_buf()).unwrap();
+        assert_eq!(
+            std::fs::read(clip_dir.join(entry_name(2))).unwrap(),
+            b"headerless"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join(VERSION_FILE)).unwrap(),
+            "1\n"
+        );
+    }
+
+    #[test]
+    fn purge_skips_unreadable_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = FileRepository::new(dir.path().to_path_buf()).unwrap();
+
+        repo.patch("ssh-ed25519 AAAA", None, paste(b"old", Some(100)), 2)
+            .unwrap();
+        let corrupt_dir = repo.clip_dir("ssh-ed25519 AAAA", Some("corrupt"));
+        std::fs::create_dir_all(&corrupt_dir).unwrap();
+        std::fs::write(corrupt_dir.join(entry_name(0)), b"CLE1\xff\xff").unwrap();
+
+        assert_eq!(repo.purge_expired(200).unwrap(), 1);
+        assert!(corrupt_dir.join(entry_name(0)).exists());
+    }
+
+    #[test]
+    fn rejects_oversized_meta() {
+        let err = Meta::read(&b"CLE1\xff\xff\xff\xff"[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,30 @@
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+use cliplink_crypto::{Ed25519PrivKey, PrivKey, RsaError};
+use tracing::info;
+
+/// Loads the server host key, generating a new Ed25519 key on first start.
+pub fn load_or_generate(path: &Path) -> Result<PrivKey, RsaError> {
+    if path.exists() {
+        return PrivKey::from_file(path);
+    }
+
+    let priv_key = Ed25519PrivKey::generate();
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+        .open(path)?
+        .write_all(priv_key.to_openssh()?.as_bytes())?;
+
+    info!("generated host key at {path:?}");
+
+    Ok(PrivKey::Ed25519(priv_key))
+}
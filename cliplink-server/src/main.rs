@@ -1,52 +1,548 @@
-use cliplink_common::{PACKET_SIZE, Packet};
-use std::net::{TcpListener, TcpStream};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::Parser;
+use cliplink_crypto::PrivKey;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+    task::JoinSet,
+};
+use tracing::{Instrument, error, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
 
 use crate::{
-    conn::{Connection, ConnectionError},
-    repository::InMemoryRepository,
+    authorized_keys::AuthorizedKeys,
+    config::{ConfigError, ConfigLayer, ServerConfig, Storage, StorageKind},
+    conn::Connection,
+    file_repository::FileRepository,
+    repository::{InMemoryRepository, Repository, unix_time},
     session::{Session, SessionError},
+    sqlite_repository::SqliteRepository,
 };
 
+mod authorized_keys;
+mod config;
 mod conn;
+mod file_repository;
+mod host_key;
 mod repository;
 mod session;
+mod sqlite_repository;
+
+/// How often expired clips are purged.
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
 
-fn main() {
-    let addr = std::env::var("CL_ADDR").unwrap_or("127.0.0.1".into());
-    let port = std::env::var("CL_PORT").unwrap_or("6166".into());
-    let bind = format!("{addr}:{port}");
+/// Cliplink server
+///
+/// Settings are read from /etc/cliplink/server.toml, then ~/.config/cliplink/server.toml, then
+/// CL_* environment variables, then these flags, each overriding the ones before.
+#[derive(Parser, Debug)]
+#[command(name = "cliplink-server", version, about, long_about)]
+struct Args {
+    /// Read settings from this file instead of the system and user config files
+    #[arg(long)]
+    config: Option<PathBuf>,
 
-    let socket = TcpListener::bind(&bind).expect("failed to bind to {bind}");
+    /// Validate the settings, host key and authorized_keys, print them and exit
+    #[arg(long)]
+    check_config: bool,
 
-    println!("listening on {:?}", socket.local_addr().unwrap());
+    /// Address to listen on, may be given more than once
+    #[arg(long = "addr")]
+    addrs: Vec<IpAddr>,
 
-    for stream in socket.incoming() {
-        let stream = match stream {
-            Ok(stream) => {
-                println!("incoming connection: {:?}", stream.peer_addr().unwrap());
-                stream
+    /// Port to listen on
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Where to keep clips [default: memory]
+    #[arg(long)]
+    storage: Option<StorageKind>,
+
+    /// Directory holding the file or SQLite storage [default: ~/.local/share/cliplink/data]
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+
+    /// Log filter, e.g. info or warn,cliplink_server=debug
+    #[arg(long)]
+    log: Option<String>,
+}
+
+impl Args {
+    fn flags(&self) -> ConfigLayer {
+        ConfigLayer {
+            addrs: (!self.addrs.is_empty()).then(|| self.addrs.clone()),
+            port: self.port,
+            storage: self.storage,
+            data_dir: self.data_dir.clone(),
+            log: self.log.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let config = match ServerConfig::load(args.config.as_deref(), args.flags()) {
+        Ok(config) => Arc::new(config),
+        Err(err) => {
+            eprintln!("cliplink-server: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if args.check_config {
+        println!("{config:#?}");
+        return match check_files(&config) {
+            Ok(()) => {
+                println!("configuration ok");
+                ExitCode::SUCCESS
             }
             Err(err) => {
-                eprintln!("incoming connection error: {err:?}");
+                eprintln!("cliplink-server: {err}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&config.log))
+        .init();
+
+    for file in &config.files {
+        info!("read settings from {file:?}");
+    }
+
+    let host_key = match host_key::load_or_generate(&config.host_key) {
+        Ok(host_key) => Arc::new(host_key),
+        Err(err) => {
+            error!("failed to load host key from {:?}: {err}", config.host_key);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match host_key.pub_key().fingerprint() {
+        Ok(fingerprint) => info!("host key fingerprint: {fingerprint}"),
+        Err(err) => {
+            error!(
+                "failed to fingerprint host key {:?}: {err}",
+                config.host_key
+            );
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let authorized_keys = Arc::new(AuthorizedKeys::new(config.authorized_keys.clone()));
+
+    let repo: Arc<dyn Repository> = match &config.storage {
+        Storage::Memory => Arc::new(InMemoryRepository::default()),
+        Storage::File { data_dir } => match FileRepository::new(data_dir.clone()) {
+            Ok(repo) => {
+                info!("storing clips in {data_dir:?}");
+                Arc::new(repo)
+            }
+            Err(err) => {
+                error!("failed to open data dir {data_dir:?}: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+        Storage::Sqlite { path } => match SqliteRepository::open(path) {
+            Ok(repo) => {
+                info!("storing clips in {path:?}");
+                Arc::new(repo)
+            }
+            Err(err) => {
+                error!("failed to open database {path:?}: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    tokio::spawn(reap(repo.clone()));
+
+    // Shared by every listener, so the limit holds across addresses.
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+    let mut listeners = JoinSet::new();
+
+    for addr in &config.addrs {
+        let bind = SocketAddr::new(*addr, config.port);
+
+        let socket = match TcpListener::bind(bind).await {
+            Ok(socket) => socket,
+            Err(err) => {
+                error!("failed to bind to {bind}: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+
+        info!("listening on {:?}", socket.local_addr().unwrap_or(bind));
+
+        listeners.spawn(accept(
+            socket,
+            connections.clone(),
+            config.clone(),
+            host_key.clone(),
+            authorized_keys.clone(),
+            repo.clone(),
+        ));
+    }
+
+    info!("serving up to {} connections", config.max_connections);
+
+    listeners.join_all().await;
+    ExitCode::SUCCESS
+}
+
+/// Parses the host key and `authorized_keys` named by `config`, without creating either.
+fn check_files(config: &ServerConfig) -> Result<(), ConfigError> {
+    let path = &config.host_key;
+    match path.exists() {
+        true => {
+            let host_key = PrivKey::from_file(path)
+                .map_err(|err| ConfigError::invalid("host-key", path.display(), err))?;
+            let fingerprint = host_key
+                .pub_key()
+                .fingerprint()
+                .map_err(|err| ConfigError::invalid("host-key", path.display(), err))?;
+            println!("host key {path:?}: {fingerprint}");
+        }
+        false => println!("host key {path:?} will be generated on first start"),
+    }
+
+    let path = &config.authorized_keys;
+    let keys = AuthorizedKeys::new(path.clone())
+        .check()
+        .map_err(|err| ConfigError::invalid("authorized-keys", path.display(), err))?;
+    println!("{keys} authorized keys in {path:?}");
+
+    Ok(())
+}
+
+/// Accepts connections on `socket` for the lifetime of the server, each handled in its own task.
+async fn accept(
+    socket: TcpListener,
+    connections: Arc<Semaphore>,
+    config: Arc<ServerConfig>,
+    host_key: Arc<PrivKey>,
+    authorized_keys: Arc<AuthorizedKeys>,
+    repo: Arc<dyn Repository>,
+) {
+    loop {
+        let (stream, peer) = match socket.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("incoming connection error: {err}");
                 continue;
             }
         };
 
-        std::thread::spawn(move || {
-            handle(stream);
-        });
+        // Closing right away tells the client to retry later, rather than leaving it queued
+        // behind connections that may take up to the idle timeout to go away.
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            warn!(%peer, "connection limit reached, closing connection");
+            continue;
+        };
+
+        let span = info_span!("conn", %peer);
+        span.in_scope(|| info!("incoming connection"));
+
+        let config = config.clone();
+        let host_key = host_key.clone();
+        let authorized_keys = authorized_keys.clone();
+        let repo = repo.clone();
+        tokio::spawn(
+            async move {
+                if let Err(err) = handle(stream, config, host_key, authorized_keys, repo).await {
+                    warn!("connection error: {err}");
+                }
+                drop(permit);
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Purges expired clips every [`REAP_INTERVAL`], for the lifetime of the server.
+async fn reap(repo: Arc<dyn Repository>) {
+    loop {
+        tokio::time::sleep(REAP_INTERVAL).await;
+
+        let repo = repo.clone();
+        match tokio::task::spawn_blocking(move || repo.purge_expired(unix_time())).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(purged)) => info!("purged {purged} expired clips"),
+            Ok(Err(err)) => error!("failed to purge expired clips: {err}"),
+            Err(err) => error!("failed to purge expired clips: {err}"),
+        }
     }
 }
 
-fn handle(stream: TcpStream) -> Result<(), SessionError> {
-    let mut buf = [0u8; PACKET_SIZE];
-    let mut conn = Connection::from(stream);
+async fn handle(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+    host_key: Arc<PrivKey>,
+    authorized_keys: Arc<AuthorizedKeys>,
+    repo: Arc<dyn Repository>,
+) -> Result<(), SessionError> {
+    let mut conn = Connection::from(stream, host_key, config.read_timeout);
+
+    let frame = conn.read_frame().await?;
+    let conn = conn.validate_ssh_key(&frame, &authorized_keys).await?;
+    let mut conn = conn.gen_session_keys().await?;
 
-    let _ = conn.read_bytes(&mut buf).map_err(ConnectionError::from)?;
-    let conn = conn.validate_ssh_key(&Packet::from_bytes(&buf))?;
-    let conn = conn.gen_aes256_key()?;
-    let mut session = Session::new(conn, Box::new(InMemoryRepository::default())); // TODO:
+    let frame = conn.read_frame().await?;
+    let conn = conn.verify_auth(&frame).await?;
+    info!("authenticated {:?}", conn.authorized_key().comment);
 
-    session.blocking_handle()?;
+    let mut session = Session::new(conn, repo, config);
+
+    session.handle().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, time::Instant};
+
+    use cliplink_common::{
+        Decoder, Encoder, Frame, TransferHeader, read_frame, read_frame_async, write_frame,
+        write_frame_async,
+    };
+    use cliplink_crypto::{
+        ClientKex, Direction, Ed25519PrivKey, KexMode, RsaPadding, SessionKeys, Transcript,
+        sequence_nonce,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::conn::SECURE_FRAME_TYPE;
+
+    fn config(
+        max_connections: usize,
+        idle_timeout: Duration,
+        read_timeout: Duration,
+    ) -> ServerConfig {
+        ServerConfig {
+            addrs: vec![IpAddr::from([127, 0, 0, 1])],
+            port: 0,
+            max_clip_size: 1024,
+            history_depth: 1,
+            key_history_depth: Default::default(),
+            max_ttl: None,
+            max_connections,
+            idle_timeout,
+            read_timeout,
+            host_key: PathBuf::new(),
+            authorized_keys: PathBuf::new(),
+            storage: Storage::Memory,
+            log: String::new(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Serves `config` on a free local port to `client` alone, returning the address and the
+    /// connection limit.
+    async fn serve(
+        config: ServerConfig,
+        client: &PrivKey,
+        dir: &Path,
+    ) -> (SocketAddr, Arc<Semaphore>) {
+        let path = dir.join("authorized_keys");
+        std::fs::write(&path, client.pub_key().to_openssh(None).unwrap()).unwrap();
+
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let connections = Arc::new(Semaphore::new(config.max_connections));
+
+        tokio::spawn(accept(
+            socket,
+            connections.clone(),
+            Arc::new(config),
+            Arc::new(PrivKey::Ed25519(Ed25519PrivKey::generate())),
+            Arc::new(AuthorizedKeys::new(path)),
+            Arc::new(InMemoryRepository::default()),
+        ));
+
+        (addr, connections)
+    }
+
+    async fn wait_for_permits(connections: &Semaphore, permits: usize) {
+        let wait = async {
+            while connections.available_permits() != permits {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .expect("permits never became available");
+    }
+
+    /// Waits for the server to close `stream`, returning how long that took.
+    async fn closed(stream: &mut TcpStream) -> Duration {
+        let start = Instant::now();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut [0u8; 1]))
+            .await
+            .expect("connection was left open");
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        start.elapsed()
+    }
+
+    /// Runs the client side of the handshake, as `cliplink` does, returning the session keys.
+    async fn authenticate(stream: &mut TcpStream, key: &PrivKey) -> SessionKeys {
+        let kex = ClientKex::new(KexMode::Ephemeral, RsaPadding::Oaep);
+        let syn = Frame::new(
+            b"sshsyn",
+            &Encoder::default()
+                .put_u8(kex.mode() as u8)
+                .put_u8(kex.padding() as u8)
+                .put_str(&key.pub_key().to_openssh(None).unwrap())
+                .put_bytes(kex.eph_pub_key())
+                .finish(),
+        );
+        write_frame_async(stream, &syn).await.unwrap();
+
+        let syn_ack = read_frame_async(stream).await.unwrap();
+        assert_eq!(syn_ack.ty, b"sshsynack");
+
+        let mut transcript = Transcript::default();
+        transcript.update(&syn.payload);
+        transcript.update(&syn_ack.payload);
+        let auth = Frame::new(b"sshauth", &key.sign(&transcript.auth_message()));
+        write_frame_async(stream, &auth).await.unwrap();
+
+        assert_eq!(read_frame_async(stream).await.unwrap().ty, b"sshauthack");
+
+        let reply = Decoder::new(&syn_ack.payload).get_bytes().unwrap();
+        kex.finish(key, reply).unwrap()
+    }
+
+    /// Seals `frame` as the client does for sequence number `seq`.
+    async fn write_sec(stream: &mut TcpStream, keys: &SessionKeys, seq: u64, frame: &Frame) {
+        let mut plain_buf = Vec::new();
+        write_frame(&mut plain_buf, frame).unwrap();
+        let enc_buf = keys
+            .client
+            .encrypt_with_nonce(sequence_nonce(Direction::ClientToServer, seq), &plain_buf)
+            .unwrap();
+
+        let mut frame = Frame::new(SECURE_FRAME_TYPE, &enc_buf);
+        frame.request_id = seq;
+        write_frame_async(stream, &frame).await.unwrap();
+    }
+
+    /// Reads the frame the server sealed for sequence number `seq`.
+    async fn read_sec(stream: &mut TcpStream, keys: &SessionKeys, seq: u64) -> Frame {
+        let frame = read_frame_async(stream).await.unwrap();
+        let plain_buf = keys
+            .server
+            .decrypt(
+                sequence_nonce(Direction::ServerToClient, seq),
+                &frame.payload,
+            )
+            .unwrap();
+
+        read_frame(&mut plain_buf.as_slice()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn connection_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = PrivKey::Ed25519(Ed25519PrivKey::generate());
+        let config = config(1, Duration::from_secs(60), Duration::from_secs(60));
+        let (addr, connections) = serve(config, &key, dir.path()).await;
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        wait_for_permits(&connections, 0).await;
+
+        // Over the limit: closed as soon as accepted, while the first one is still served.
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        closed(&mut second).await;
+        authenticate(&mut first, &key).await;
+
+        drop(first);
+        wait_for_permits(&connections, 1).await;
+
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        authenticate(&mut third, &key).await;
+    }
+
+    #[tokio::test]
+    async fn read_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = PrivKey::Ed25519(Ed25519PrivKey::generate());
+        let config = config(4, Duration::from_secs(60), Duration::from_millis(200));
+        let (addr, _) = serve(config, &key, dir.path()).await;
+
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        assert!(closed(&mut silent).await >= Duration::from_millis(150));
+
+        // A frame that starts arriving must also finish within the timeout.
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+        stalled.write_all(&[0, 0]).await.unwrap();
+        assert!(closed(&mut stalled).await >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = PrivKey::Ed25519(Ed25519PrivKey::generate());
+        let config = config(4, Duration::from_millis(300), Duration::from_secs(60));
+        let (addr, connections) = serve(config, &key, dir.path()).await;
+
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        authenticate(&mut idle, &key).await;
+
+        // Well before the read timeout.
+        assert!(closed(&mut idle).await >= Duration::from_millis(250));
+        wait_for_permits(&connections, 4).await;
+    }
+
+    #[tokio::test]
+    async fn denies_overflowing_paste() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = PrivKey::Ed25519(Ed25519PrivKey::generate());
+        let config = config(4, Duration::from_secs(60), Duration::from_secs(60));
+        let (addr, _) = serve(config, &key, dir.path()).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let keys = authenticate(&mut stream, &key).await;
+
+        let begin = |buf: &[u8]| {
+            let payload = Encoder::default()
+                .put_str("")
+                .put_bytes(&TransferHeader::new(buf).to_bytes())
+                .put_u64(0)
+                .put_u8(0)
+                .put_str("")
+                .put_str("")
+                .put_u8(0)
+                .finish();
+            Frame::new(b"pastebegin", &payload)
+        };
+
+        // More chunks than announced: denied at commit, once the extra chunks are drained.
+        write_sec(&mut stream, &keys, 0, &begin(b"clip")).await;
+        assert_eq!(read_sec(&mut stream, &keys, 0).await.ty, b"pastebeginack");
+        write_sec(&mut stream, &keys, 1, &Frame::new(b"pastechunk", b"clip")).await;
+        write_sec(&mut stream, &keys, 2, &Frame::new(b"pastechunk", b"more")).await;
+        write_sec(&mut stream, &keys, 3, &Frame::new(b"pastecommit", &[])).await;
+        assert_eq!(read_sec(&mut stream, &keys, 1).await.ty, b"pastedeny");
+
+        // The session goes on.
+        write_sec(&mut stream, &keys, 4, &begin(b"clip")).await;
+        assert_eq!(read_sec(&mut stream, &keys, 2).await.ty, b"pastebeginack");
+        write_sec(&mut stream, &keys, 5, &Frame::new(b"pastechunk", b"clip")).await;
+        write_sec(&mut stream, &keys, 6, &Frame::new(b"pastecommit", &[])).await;
+        assert_eq!(read_sec(&mut stream, &keys, 3).await.ty, b"pasteack");
+    }
+}
@@ -1,53 +1,575 @@
-use std::{collections::HashMap, sync::LazyLock};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-pub trait Repository<T, E> {
-    fn get(&self, id: &String, clip: Option<&String>) -> Result<&T, E>;
-    fn patch(&mut self, id: &String, clip: Option<&String>, payload: T) -> Result<(), E>;
-}
+use cliplink_common::{DEFAULT_CLIP, HistoryEntry};
+use tracing::debug;
 
 #[derive(Debug, thiserror::Error)]
-pub enum InMemoryRepositoryError {
+pub enum RepositoryError {
     #[error("not found")]
     NotFound,
+
+    #[error("expired")]
+    Expired,
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+
+    #[error(transparent)]
+    SqliteError(#[from] rusqlite::Error),
 }
 
-#[derive(Default)]
-pub struct InMemoryRepository<T>(HashMap<String, HashMap<String, T>>);
+/// A clip to store.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Paste {
+    pub payload: Vec<u8>,
+
+    /// MIME type of `payload`, e.g. `text/plain; charset=utf-8`.
+    pub content_type: Option<String>,
+
+    /// Name of the file the clip was pasted from.
+    pub filename: Option<String>,
+
+    /// Seconds since the Unix epoch after which the clip can no longer be copied.
+    pub expires_at: Option<u64>,
 
-impl<T> InMemoryRepository<T> {
-    const DEFAULT_CLIP: LazyLock<String> = LazyLock::new(|| String::from("default"));
+    /// Burn after reading: the first successful `get` deletes the clip.
+    pub once: bool,
+
+    /// `payload` was encrypted by the client with a key the server doesn't have.
+    pub e2e: bool,
 }
 
-impl<T> Repository<T, InMemoryRepositoryError> for InMemoryRepository<T>
-where
-    T: Into<Vec<u8>>,
-{
-    fn get(&self, id: &String, clip: Option<&String>) -> Result<&T, InMemoryRepositoryError> {
-        println!("get id: {id}, clip: {clip:?}");
-        self.0
-            .get(id)
-            .map(|item| item.get(clip.unwrap_or(&*Self::DEFAULT_CLIP)))
-            .ok_or(InMemoryRepositoryError::NotFound)?
-            .ok_or(InMemoryRepositoryError::NotFound)
+impl Paste {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
     }
+}
+
+/// A burn-after-read entry handed out by [`Repository::get`]. Other callers are told it doesn't
+/// exist until [`Repository::commit`] deletes it or [`Repository::release`] puts it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Claim {
+    pub id: String,
+    pub clip: String,
+
+    /// The claimed entry, as the backend numbers them.
+    pub entry: u64,
+}
 
+/// A clip slot as listed by [`Repository::list`], described by its latest entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClipInfo {
+    pub name: String,
+    pub size: u64,
+    pub content_type: Option<String>,
+
+    /// Seconds since the Unix epoch.
+    pub updated_at: u64,
+
+    /// Entries kept in the history.
+    pub entries: usize,
+}
+
+/// Clip store shared by every connection.
+///
+/// Each clip keeps a ring of its most recent entries, index `0` being the latest paste.
+/// Expired entries keep their place in the ring until [`Repository::purge_expired`] removes them.
+///
+/// Implementations synchronize internally, so a single instance can sit behind an
+/// `Arc<dyn Repository>` and be used from all connection threads at once.
+pub trait Repository: Send + Sync {
+    /// Returns the entry `index` pastes back in the history of `clip`, or
+    /// [`RepositoryError::Expired`] if it outlived its TTL.
+    ///
+    /// A [`Paste::once`] entry comes with a [`Claim`] taken in the same atomic step, so
+    /// concurrent callers can't both get it. The caller settles the claim once it knows whether
+    /// the entry was delivered.
+    fn get(
+        &self,
+        id: &str,
+        clip: Option<&str>,
+        index: usize,
+    ) -> Result<(Paste, Option<Claim>), RepositoryError>;
+
+    /// Deletes a claimed entry, after it was delivered.
+    fn commit(&self, claim: &Claim) -> Result<(), RepositoryError>;
+
+    /// Makes a claimed entry available again, after it failed to be delivered.
+    fn release(&self, claim: &Claim) -> Result<(), RepositoryError>;
+
+    /// Pushes `paste` as the latest entry of `clip`, dropping all but the `depth` most recent.
     fn patch(
-        &mut self,
-        id: &String,
-        clip: Option<&String>,
-        payload: T,
-    ) -> Result<(), InMemoryRepositoryError> {
-        println!("patch id: {id}, clip: {clip:?}");
-        let clip_store = match self.0.get_mut(id) {
-            Some(store) => store,
-            None => {
-                self.0.insert(id.clone(), HashMap::new());
-                self.0.get_mut(id).unwrap()
-            }
+        &self,
+        id: &str,
+        clip: Option<&str>,
+        paste: Paste,
+        depth: usize,
+    ) -> Result<(), RepositoryError>;
+
+    /// Lists the history of `clip`, latest first.
+    fn history(&self, id: &str, clip: Option<&str>) -> Result<Vec<HistoryEntry>, RepositoryError>;
+
+    /// Lists the clips of `id` that hold at least one entry, by name.
+    fn list(&self, id: &str) -> Result<Vec<ClipInfo>, RepositoryError>;
+
+    /// Deletes `clip` along with its whole history.
+    fn delete(&self, id: &str, clip: Option<&str>) -> Result<(), RepositoryError>;
+
+    /// Deletes every entry that expired at `now`, returning how many were deleted.
+    fn purge_expired(&self, now: u64) -> Result<usize, RepositoryError>;
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+struct Entry {
+    paste: Paste,
+    created_at: u64,
+
+    /// Numbers entries across the repository, for claims to find theirs.
+    seq: u64,
+    claimed: bool,
+}
+
+type Items = HashMap<String, HashMap<String, VecDeque<Entry>>>;
+
+#[derive(Default)]
+pub struct InMemoryRepository(RwLock<Items>, AtomicU64);
+
+impl InMemoryRepository {
+    /// Runs `f` on the entry `claim` points at, if it is still there.
+    fn claimed(&self, claim: &Claim, f: impl FnOnce(&mut VecDeque<Entry>, usize)) {
+        let mut items = self.0.write().expect("repository lock poisoned");
+        let Some(entries) = items
+            .get_mut(&claim.id)
+            .and_then(|item| item.get_mut(&claim.clip))
+        else {
+            return;
         };
 
-        clip_store.insert(clip.unwrap_or(&*Self::DEFAULT_CLIP).clone(), payload);
+        if let Some(index) = entries.iter().position(|entry| entry.seq == claim.entry) {
+            f(entries, index);
+        }
+    }
+}
+
+impl Repository for InMemoryRepository {
+    fn get(
+        &self,
+        id: &str,
+        clip: Option<&str>,
+        index: usize,
+    ) -> Result<(Paste, Option<Claim>), RepositoryError> {
+        debug!("get id: {id}, clip: {clip:?}, index: {index}");
+        let clip = clip.unwrap_or(DEFAULT_CLIP);
+        let mut items = self.0.write().expect("repository lock poisoned");
+        let entry = items
+            .get_mut(id)
+            .and_then(|item| item.get_mut(clip))
+            .and_then(|entries| entries.get_mut(index))
+            .filter(|entry| !entry.claimed)
+            .ok_or(RepositoryError::NotFound)?;
+
+        if entry.paste.is_expired(unix_time()) {
+            return Err(RepositoryError::Expired);
+        }
+
+        let claim = entry.paste.once.then(|| {
+            entry.claimed = true;
+            Claim {
+                id: id.to_string(),
+                clip: clip.to_string(),
+                entry: entry.seq,
+            }
+        });
+
+        Ok((entry.paste.clone(), claim))
+    }
+
+    fn commit(&self, claim: &Claim) -> Result<(), RepositoryError> {
+        self.claimed(claim, |entries, index| {
+            entries.remove(index);
+        });
+
+        Ok(())
+    }
+
+    fn release(&self, claim: &Claim) -> Result<(), RepositoryError> {
+        self.claimed(claim, |entries, index| entries[index].claimed = false);
 
         Ok(())
     }
+
+    fn patch(
+        &self,
+        id: &str,
+        clip: Option<&str>,
+        paste: Paste,
+        depth: usize,
+    ) -> Result<(), RepositoryError> {
+        debug!("patch id: {id}, clip: {clip:?}");
+        let mut items = self.0.write().expect("repository lock poisoned");
+        let entries = items
+            .entry(id.to_string())
+            .or_default()
+            .entry(clip.unwrap_or(DEFAULT_CLIP).to_string())
+            .or_default();
+
+        entries.push_front(Entry {
+            paste,
+            created_at: unix_time(),
+            seq: self.1.fetch_add(1, Ordering::Relaxed),
+            claimed: false,
+        });
+        entries.truncate(depth);
+
+        Ok(())
+    }
+
+    fn history(&self, id: &str, clip: Option<&str>) -> Result<Vec<HistoryEntry>, RepositoryError> {
+        Ok(self
+            .0
+            .read()
+            .expect("repository lock poisoned")
+            .get(id)
+            .and_then(|item| item.get(clip.unwrap_or(DEFAULT_CLIP)))
+            .map(|entries| {
+                entries
+                    .iter()
+                    .map(|entry| HistoryEntry {
+                        size: entry.paste.payload.len() as u64,
+                        created_at: entry.created_at,
+                        expires_at: entry.paste.expires_at,
+                        once: entry.paste.once,
+                        e2e: entry.paste.e2e,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn list(&self, id: &str) -> Result<Vec<ClipInfo>, RepositoryError> {
+        let items = self.0.read().expect("repository lock poisoned");
+
+        let mut clips: Vec<_> = items
+            .get(id)
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, entries)| {
+                let latest = entries.front()?;
+
+                Some(ClipInfo {
+                    name: name.clone(),
+                    size: latest.paste.payload.len() as u64,
+                    content_type: latest.paste.content_type.clone(),
+                    updated_at: latest.created_at,
+                    entries: entries.len(),
+                })
+            })
+            .collect();
+        clips.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(clips)
+    }
+
+    fn delete(&self, id: &str, clip: Option<&str>) -> Result<(), RepositoryError> {
+        debug!("delete id: {id}, clip: {clip:?}");
+        self.0
+            .write()
+            .expect("repository lock poisoned")
+            .get_mut(id)
+            .and_then(|item| item.remove(clip.unwrap_or(DEFAULT_CLIP)))
+            .filter(|entries| !entries.is_empty())
+            .map(|_| ())
+            .ok_or(RepositoryError::NotFound)
+    }
+
+    fn purge_expired(&self, now: u64) -> Result<usize, RepositoryError> {
+        let mut items = self.0.write().expect("repository lock poisoned");
+        let mut purged = 0;
+
+        items.retain(|_, clips| {
+            clips.retain(|_, entries| {
+                let len = entries.len();
+                entries.retain(|entry| !entry.paste.is_expired(now));
+                purged += len - entries.len();
+
+                !entries.is_empty()
+            });
+
+            !clips.is_empty()
+        });
+
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn paste(payload: &[u8]) -> Paste {
+        Paste {
+            payload: payload.to_vec(),
+            ..Paste::default()
+        }
+    }
+
+    /// Checks the behavior every [`Repository`] implementation shares. Each part works on its own
+    /// identity, so they don't see each other's clips.
+    pub(crate) fn conformance(repo: &dyn Repository) {
+        history_ring(repo);
+        expiry(repo);
+        burn_after_read(repo);
+        list_delete(repo);
+        keeps_e2e_flag(repo);
+        concurrent_patch_delete(repo);
+    }
+
+    fn history_ring(repo: &dyn Repository) {
+        let id = "ssh-ed25519 RING";
+
+        assert!(matches!(
+            repo.get(id, None, 0),
+            Err(RepositoryError::NotFound)
+        ));
+        assert!(repo.history(id, None).unwrap().is_empty());
+
+        for payload in [&b"first"[..], b"second", b"third"] {
+            repo.patch(id, None, paste(payload), 2).unwrap();
+        }
+        repo.patch(id, Some("work"), paste(b"work"), 2).unwrap();
+
+        assert_eq!(repo.get(id, None, 0).unwrap().0.payload, b"third");
+        assert_eq!(repo.get(id, None, 1).unwrap().0.payload, b"second");
+        assert!(matches!(
+            repo.get(id, None, 2),
+            Err(RepositoryError::NotFound)
+        ));
+        assert_eq!(
+            repo.history(id, None)
+                .unwrap()
+                .iter()
+                .map(|entry| entry.size)
+                .collect::<Vec<_>>(),
+            [5, 6]
+        );
+        assert_eq!(repo.get(id, Some("work"), 0).unwrap().0.payload, b"work");
+    }
+
+    fn expiry(repo: &dyn Repository) {
+        let id = "ssh-ed25519 TTL";
+        let now = unix_time();
+
+        repo.patch(
+            id,
+            None,
+            Paste {
+                expires_at: Some(now + 60),
+                ..paste(b"kept")
+            },
+            10,
+        )
+        .unwrap();
+        repo.patch(
+            id,
+            None,
+            Paste {
+                expires_at: Some(now - 1),
+                ..paste(b"token")
+            },
+            10,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            repo.get(id, None, 0),
+            Err(RepositoryError::Expired)
+        ));
+        assert_eq!(repo.purge_expired(now).unwrap(), 1);
+        assert_eq!(repo.get(id, None, 0).unwrap().0.payload, b"kept");
+        assert_eq!(
+            repo.history(id, None).unwrap()[0].expires_at,
+            Some(now + 60)
+        );
+        assert_eq!(repo.purge_expired(now).unwrap(), 0);
+    }
+
+    fn burn_after_read(repo: &dyn Repository) {
+        let id = "ssh-ed25519 ONCE";
+
+        repo.patch(id, None, paste(b"kept"), 10).unwrap();
+        repo.patch(
+            id,
+            None,
+            Paste {
+                once: true,
+                ..paste(b"secret")
+            },
+            10,
+        )
+        .unwrap();
+
+        let mut claims: Vec<_> = std::thread::scope(|scope| {
+            let readers: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| repo.get(id, None, 0)))
+                .collect();
+            readers
+                .into_iter()
+                .filter_map(|reader| match reader.join().unwrap() {
+                    Ok((paste, claim)) => {
+                        assert_eq!(paste.payload, b"secret");
+                        claim
+                    }
+                    Err(RepositoryError::NotFound) => None,
+                    Err(err) => panic!("get failed: {err}"),
+                })
+                .collect()
+        });
+        assert_eq!(claims.len(), 1);
+        let claim = claims.pop().unwrap();
+
+        // Released, the entry can be claimed again.
+        repo.release(&claim).unwrap();
+        let (secret, claim) = repo.get(id, None, 0).unwrap();
+        assert_eq!(secret.payload, b"secret");
+        let claim = claim.unwrap();
+        assert!(matches!(
+            repo.get(id, None, 0),
+            Err(RepositoryError::NotFound)
+        ));
+
+        repo.commit(&claim).unwrap();
+        assert_eq!(repo.get(id, None, 0).unwrap(), (paste(b"kept"), None));
+        assert_eq!(repo.history(id, None).unwrap().len(), 1);
+
+        // Settling a claim twice is harmless.
+        repo.commit(&claim).unwrap();
+        repo.release(&claim).unwrap();
+        assert_eq!(repo.history(id, None).unwrap().len(), 1);
+
+        // A claim outliving its clip doesn't settle on the clip pasted in its place.
+        let once = Paste {
+            once: true,
+            ..paste(b"secret")
+        };
+        repo.patch(id, Some("gone"), once.clone(), 10).unwrap();
+        let (_, claim) = repo.get(id, Some("gone"), 0).unwrap();
+        repo.delete(id, Some("gone")).unwrap();
+        repo.patch(id, Some("gone"), once.clone(), 10).unwrap();
+        repo.commit(&claim.unwrap()).unwrap();
+        assert_eq!(repo.get(id, Some("gone"), 0).unwrap().0, once);
+    }
+
+    fn list_delete(repo: &dyn Repository) {
+        let id = "ssh-ed25519 LIST";
+
+        repo.patch(id, Some("work"), paste(b"one"), 10).unwrap();
+        repo.patch(id, Some("work"), paste(b"two!"), 10).unwrap();
+        repo.patch(id, None, paste(b"default"), 10).unwrap();
+
+        assert_eq!(
+            repo.list(id)
+                .unwrap()
+                .iter()
+                .map(|clip| (clip.name.as_str(), clip.size, clip.entries))
+                .collect::<Vec<_>>(),
+            [(DEFAULT_CLIP, 7, 1), ("work", 4, 2)]
+        );
+
+        repo.delete(id, Some("work")).unwrap();
+        assert!(matches!(
+            repo.delete(id, Some("work")),
+            Err(RepositoryError::NotFound)
+        ));
+        assert_eq!(repo.list(id).unwrap().len(), 1);
+        assert!(repo.list("ssh-ed25519 NONE").unwrap().is_empty());
+    }
+
+    fn keeps_e2e_flag(repo: &dyn Repository) {
+        let id = "ssh-ed25519 E2E";
+        let sealed = Paste {
+            e2e: true,
+            ..paste(b"ciphertext")
+        };
+
+        repo.patch(id, None, sealed.clone(), 2).unwrap();
+
+        assert_eq!(repo.get(id, None, 0).unwrap(), (sealed, None));
+        assert!(repo.history(id, None).unwrap()[0].e2e);
+    }
+
+    /// Pastes racing deletes of the same clip must all land, before or after each delete.
+    fn concurrent_patch_delete(repo: &dyn Repository) {
+        let id = "ssh-ed25519 RACE";
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for n in 0..200u8 {
+                    repo.patch(id, None, paste(&[n]), 2).unwrap();
+                }
+            });
+            scope.spawn(|| {
+                for _ in 0..200 {
+                    match repo.delete(id, None) {
+                        Ok(()) | Err(RepositoryError::NotFound) => {}
+                        Err(err) => panic!("delete failed: {err}"),
+                    }
+                }
+            });
+        });
+
+        repo.patch(id, None, paste(b"last"), 2).unwrap();
+        assert_eq!(repo.get(id, None, 0).unwrap().0.payload, b"last");
+    }
+
+    #[test]
+    fn in_memory_conforms() {
+        conformance(&InMemoryRepository::default());
+    }
+
+    #[test]
+    fn shared_across_threads() {
+        let repo: Arc<dyn Repository> = Arc::new(InMemoryRepository::default());
+
+        let writers: Vec<_> = (0..4)
+            .map(|n| {
+                let repo = repo.clone();
+                std::thread::spawn(move || {
+                    repo.patch("ssh-ed25519 AAAA", Some(&n.to_string()), paste(&[n]), 1)
+                        .unwrap()
+                })
+            })
+            .collect();
+        writers
+            .into_iter()
+            .for_each(|writer| writer.join().unwrap());
+
+        for n in 0..4u8 {
+            assert_eq!(
+                repo.get("ssh-ed25519 AAAA", Some(&n.to_string()), 0)
+                    .unwrap()
+                    .0
+                    .payload,
+                vec![n]
+            );
+        }
+        assert!(matches!(
+            repo.get("ssh-ed25519 AAAA", None, 0),
+            Err(RepositoryError::NotFound)
+        ));
+    }
 }
@@ -1,10 +1,15 @@
-use std::u8;
+use std::sync::Arc;
 
-use cliplink_common::{Packet, PacketError};
+use cliplink_common::{
+    CHUNK_SIZE, ClipName, CodecError, ContentError, Decoder, Encoder, Frame, HistoryEntry,
+    Transfer, TransferError, TransferHeader, validate_content_type, validate_filename,
+};
+use tracing::{info, warn};
 
 use crate::{
+    config::ServerConfig,
     conn::{Connection, ConnectionError, Secure},
-    repository::Repository,
+    repository::{Paste, Repository, RepositoryError, unix_time},
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -16,40 +21,240 @@ pub enum SessionError {
     ConnectionError(#[from] ConnectionError),
 
     #[error(transparent)]
-    PacketError(#[from] PacketError),
+    TransferError(#[from] TransferError),
+
+    #[error(transparent)]
+    CodecError(#[from] CodecError),
 
-    #[error("{0:?}")]
-    RepositoryError(String),
+    #[error(transparent)]
+    RepositoryError(#[from] RepositoryError),
 }
 
-pub struct Session<E>(Connection<Secure>, Box<dyn Repository<Vec<u8>, E>>);
+pub struct Session(Connection<Secure>, Arc<dyn Repository>, Arc<ServerConfig>);
 
-impl<E: std::error::Error> Session<E> {
-    pub fn new(conn: Connection<Secure>, repo: Box<dyn Repository<Vec<u8>, E>>) -> Self {
-        Self(conn, repo)
+// paste:
+// pastebegin (clip, size, digest, ttl, once, content type, filename, e2e)
+//                                 > pastebeginack | pastedeny
+// pastechunk (payload) ...        >
+// pastecommit                     > pasteack | pastedeny
+//
+// copy:
+// copy (clip, index)              > copybegin (size, digest, content type, filename, e2e)
+//                                   | copydeny
+//                                 < copychunk (payload) ...
+//                                 < copycommit
+//
+// history:
+// history (clip)                  > historyack (count, (size, created at, expires at, once,
+//                                   e2e) ...) | historydeny
+//
+// list:
+// list                            > listack (count, (clip, size, content type, updated at,
+//                                   entries) ...)
+//
+// delete:
+// delete (clip)                   > deleteack | deletedeny
+//
+// An empty clip name selects the default clip. History index 0 is the latest paste. A ttl of 0
+// means the clip lives as long as the server allows. Copying an expired clip is denied with
+// "expired" rather than "not found". A once clip is deleted once the first copy that gets it was
+// sent up to copycommit, other copies being told it doesn't exist meanwhile, and is kept if sending
+// fails. An empty content type or filename means none was given. An e2e payload was encrypted by
+// the client under a key derived from its identity: the server stores and returns it as is.
+//
+// The server closes sessions that send no request for the idle timeout, and connections where any
+// single frame takes longer than the read timeout.
+impl Session {
+    pub fn new(
+        conn: Connection<Secure>,
+        repo: Arc<dyn Repository>,
+        config: Arc<ServerConfig>,
+    ) -> Self {
+        Self(conn, repo, config)
     }
 
-    pub fn blocking_handle(&mut self) -> Result<(), SessionError> {
+    pub async fn handle(&mut self) -> Result<(), SessionError> {
         loop {
-            let packet = self.0.read_packet_sec()?;
+            let frame = self.0.read_packet_sec_within(self.2.idle_timeout).await?;
 
-            match packet.ty()? {
+            match frame.ty.as_slice() {
                 b"copy" => {
-                    println!("copy");
-                    let payload = self
-                        .1
-                        .get(&self.0.id()?, None)
-                        .map_err(|err| SessionError::RepositoryError(err.to_string()))?;
+                    let mut decoder = Decoder::new(&frame.payload);
+                    let clip = decoder.get_str()?;
+                    let index = decoder.get_u32()?;
+                    decoder.finish()?;
+
+                    let clip = match ClipName::from_wire(clip) {
+                        Ok(clip) => clip,
+                        Err(err) => {
+                            self.deny(b"copydeny", &err.to_string()).await?;
+                            continue;
+                        }
+                    };
+
+                    info!("copy {clip:?} index {index}");
+                    let id = self.0.id()?;
+                    let name = clip.clone();
+                    let (paste, claim) = match self
+                        .repo(move |repo| {
+                            repo.get(&id, name.as_ref().map(ClipName::as_str), index as usize)
+                        })
+                        .await
+                    {
+                        Ok(got) => got,
+                        Err(err) => {
+                            self.deny(b"copydeny", &err.to_string()).await?;
+                            continue;
+                        }
+                    };
+
+                    let sent = self.send_clip(&paste).await;
+
+                    // A once clip is only burned after it was sent in full.
+                    if let Some(claim) = claim {
+                        match sent {
+                            Ok(()) => {
+                                self.repo(move |repo| repo.commit(&claim)).await?;
+                                info!("burned {clip:?}");
+                            }
+                            Err(_) => {
+                                if let Err(err) = self.repo(move |repo| repo.release(&claim)).await
+                                {
+                                    warn!("failed to release {clip:?}: {err}");
+                                }
+                            }
+                        }
+                    }
+                    sent?;
+                }
+                b"pastebegin" => {
+                    let mut decoder = Decoder::new(&frame.payload);
+                    let clip = decoder.get_str()?;
+                    let header = TransferHeader::from_bytes(decoder.get_bytes()?)?;
+                    let ttl = decoder.get_u64()?;
+                    let once = decoder.get_u8()? != 0;
+                    let content_type = decoder.get_str()?;
+                    let filename = decoder.get_str()?;
+                    let e2e = decoder.get_u8()? != 0;
+                    decoder.finish()?;
+
+                    let clip = match ClipName::from_wire(clip) {
+                        Ok(clip) => clip,
+                        Err(err) => {
+                            self.deny(b"pastedeny", &err.to_string()).await?;
+                            continue;
+                        }
+                    };
+
+                    let (content_type, filename) = match content_meta(content_type, filename) {
+                        Ok(meta) => meta,
+                        Err(err) => {
+                            self.deny(b"pastedeny", &err.to_string()).await?;
+                            continue;
+                        }
+                    };
+
+                    info!("paste {clip:?}");
+                    let Some(payload) = self.receive_clip(header).await? else {
+                        continue;
+                    };
+
+                    let paste = Paste {
+                        payload,
+                        content_type,
+                        filename,
+                        expires_at: self.expires_at(ttl),
+                        once,
+                        e2e,
+                    };
+                    let id = self.0.id()?;
+                    let depth = self.history_depth()?;
+                    self.repo(move |repo| {
+                        repo.patch(&id, clip.as_ref().map(ClipName::as_str), paste, depth)
+                    })
+                    .await?;
+
+                    self.0
+                        .write_packet_sec(Frame::new(b"pasteack", &[]))
+                        .await?;
+                }
+                b"history" => {
+                    let mut decoder = Decoder::new(&frame.payload);
+                    let clip = decoder.get_str()?;
+                    decoder.finish()?;
 
-                    self.0.write_packet_sec(Packet::new(b"copyack", &payload))?;
+                    let clip = match ClipName::from_wire(clip) {
+                        Ok(clip) => clip,
+                        Err(err) => {
+                            self.deny(b"historydeny", &err.to_string()).await?;
+                            continue;
+                        }
+                    };
+
+                    info!("history {clip:?}");
+                    let id = self.0.id()?;
+                    let history = self
+                        .repo(move |repo| repo.history(&id, clip.as_ref().map(ClipName::as_str)))
+                        .await?;
+
+                    self.0
+                        .write_packet_sec(Frame::new(
+                            b"historyack",
+                            &HistoryEntry::list_to_bytes(&history),
+                        ))
+                        .await?;
+                }
+                b"list" => {
+                    Decoder::new(&frame.payload).finish()?;
+
+                    let id = self.0.id()?;
+                    let clips = self.repo(move |repo| repo.list(&id)).await?;
+
+                    let mut encoder = Encoder::default();
+                    encoder.put_u32(clips.len() as u32);
+                    for clip in clips {
+                        encoder
+                            .put_str(&clip.name)
+                            .put_u64(clip.size)
+                            .put_str(clip.content_type.as_deref().unwrap_or_default())
+                            .put_u64(clip.updated_at)
+                            .put_u32(clip.entries as u32);
+                    }
+
+                    self.0
+                        .write_packet_sec(Frame::new(b"listack", &encoder.finish()))
+                        .await?;
                 }
-                b"paste" => {
-                    println!("paste");
-                    self.1
-                        .patch(&self.0.id()?, None, packet.payload()?.to_vec())
-                        .map_err(|err| SessionError::RepositoryError(err.to_string()))?;
+                b"delete" => {
+                    let mut decoder = Decoder::new(&frame.payload);
+                    let clip = decoder.get_str()?;
+                    decoder.finish()?;
 
-                    self.0.write_packet_sec(Packet::new(b"pasteack", &[]))?;
+                    let clip = match ClipName::from_wire(clip) {
+                        Ok(clip) => clip,
+                        Err(err) => {
+                            self.deny(b"deletedeny", &err.to_string()).await?;
+                            continue;
+                        }
+                    };
+
+                    info!("delete {clip:?}");
+                    let id = self.0.id()?;
+                    match self
+                        .repo(move |repo| repo.delete(&id, clip.as_ref().map(ClipName::as_str)))
+                        .await
+                    {
+                        Ok(()) => {
+                            self.0
+                                .write_packet_sec(Frame::new(b"deleteack", &[]))
+                                .await?
+                        }
+                        Err(RepositoryError::NotFound) => {
+                            self.deny(b"deletedeny", &RepositoryError::NotFound.to_string())
+                                .await?
+                        }
+                        Err(err) => return Err(err.into()),
+                    }
                 }
                 b"term" => return Ok(()),
                 ty => {
@@ -60,4 +265,143 @@ impl<E: std::error::Error> Session<E> {
             };
         }
     }
+
+    /// Entries kept per clip for the authenticated identity.
+    fn history_depth(&self) -> Result<usize, SessionError> {
+        let fingerprint = self.0.fingerprint()?;
+
+        Ok(self
+            .2
+            .key_history_depth
+            .get(&fingerprint)
+            .copied()
+            .unwrap_or(self.2.history_depth))
+    }
+
+    /// When a clip pasted with `ttl` seconds to live expires, capped by the server max TTL.
+    ///
+    /// Kept within `i64::MAX`, which SQLite stores it as.
+    fn expires_at(&self, ttl: u64) -> Option<u64> {
+        let max_ttl = self.2.max_ttl.map(|max_ttl| max_ttl.as_secs());
+
+        let ttl = match (ttl, max_ttl) {
+            (0, max_ttl) => max_ttl,
+            (ttl, Some(max_ttl)) => Some(ttl.min(max_ttl)),
+            (ttl, None) => Some(ttl),
+        };
+
+        ttl.map(|ttl| unix_time().saturating_add(ttl).min(i64::MAX as u64))
+    }
+
+    /// Runs `f` against the repository on the blocking thread pool, so slow storage never stalls
+    /// the connections sharing a runtime worker.
+    async fn repo<T, F>(&self, f: F) -> Result<T, RepositoryError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Repository) -> Result<T, RepositoryError> + Send + 'static,
+    {
+        let repo = self.1.clone();
+        let span = tracing::Span::current();
+
+        tokio::task::spawn_blocking(move || span.in_scope(|| f(repo.as_ref())))
+            .await
+            .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+    }
+
+    async fn deny(&mut self, ty: &[u8], reason: &str) -> Result<(), SessionError> {
+        Ok(self
+            .0
+            .write_packet_sec(Frame::new(ty, reason.as_bytes()))
+            .await?)
+    }
+
+    async fn send_clip(&mut self, paste: &Paste) -> Result<(), SessionError> {
+        let begin = Encoder::default()
+            .put_bytes(&TransferHeader::new(&paste.payload).to_bytes())
+            .put_str(paste.content_type.as_deref().unwrap_or_default())
+            .put_str(paste.filename.as_deref().unwrap_or_default())
+            .put_u8(paste.e2e as u8)
+            .finish();
+        self.0
+            .write_packet_sec(Frame::new(b"copybegin", &begin))
+            .await?;
+
+        for chunk in paste.payload.chunks(CHUNK_SIZE) {
+            self.0
+                .write_packet_sec(Frame::new(b"copychunk", chunk))
+                .await?;
+        }
+
+        self.0
+            .write_packet_sec(Frame::new(b"copycommit", &[]))
+            .await?;
+        Ok(())
+    }
+
+    /// Receives the chunks announced by `header`, returning `None` when the clip was denied.
+    async fn receive_clip(
+        &mut self,
+        header: TransferHeader,
+    ) -> Result<Option<Vec<u8>>, SessionError> {
+        let mut transfer = match Transfer::new(header, self.2.max_clip_size) {
+            Ok(transfer) => transfer,
+            Err(err) => {
+                self.deny(b"pastedeny", &err.to_string()).await?;
+                return Ok(None);
+            }
+        };
+
+        self.0
+            .write_packet_sec(Frame::new(b"pastebeginack", &[]))
+            .await?;
+
+        // A chunk past the announced size is denied at `pastecommit`, like a digest mismatch, so
+        // the chunks still in flight are drained first.
+        let mut pushed = Ok(());
+        loop {
+            let frame = self.0.read_packet_sec().await?;
+
+            match frame.ty.as_slice() {
+                b"pastechunk" => pushed = pushed.and_then(|()| transfer.push(&frame.payload)),
+                b"pastecommit" => break,
+                ty => {
+                    return Err(SessionError::TypeNotSupported(
+                        str::from_utf8(ty).unwrap_or_default().to_string(),
+                    ));
+                }
+            }
+        }
+
+        match pushed.and_then(|()| transfer.finish()) {
+            Ok(payload) => Ok(Some(payload)),
+            Err(err) => {
+                self.deny(b"pastedeny", &err.to_string()).await?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Validates the content type and filename of a paste, empty meaning unset.
+fn content_meta(
+    content_type: &str,
+    filename: &str,
+) -> Result<(Option<String>, Option<String>), ContentError> {
+    let content_type = match content_type {
+        "" => None,
+        content_type => {
+            validate_content_type(content_type)?;
+            Some(content_type.to_string())
+        }
+    };
+
+    let filename = match filename {
+        "" => None,
+        filename => {
+            validate_filename(filename)?;
+            Some(filename.to_string())
+        }
+    };
+
+    Ok((content_type, filename))
 }
@@ -0,0 +1,350 @@
+use std::{
+    path::Path,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use cliplink_common::{DEFAULT_CLIP, HistoryEntry};
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::repository::{Claim, ClipInfo, Paste, Repository, RepositoryError, unix_time};
+
+/// Schema migrations, applied in order. `PRAGMA user_version` records how many already ran, so
+/// only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE clips (
+        id           INTEGER PRIMARY KEY,
+        identity     TEXT NOT NULL,
+        clip         TEXT NOT NULL,
+        payload      BLOB NOT NULL,
+        content_type TEXT,
+        size         INTEGER NOT NULL,
+        created_at   INTEGER NOT NULL,
+        updated_at   INTEGER NOT NULL,
+        UNIQUE (identity, clip)
+    );
+    ",
+    // One row per history entry, the highest id being the latest.
+    "
+    CREATE TABLE clip_entries (
+        id           INTEGER PRIMARY KEY,
+        identity     TEXT NOT NULL,
+        clip         TEXT NOT NULL,
+        payload      BLOB NOT NULL,
+        content_type TEXT,
+        size         INTEGER NOT NULL,
+        created_at   INTEGER NOT NULL,
+        updated_at   INTEGER NOT NULL
+    );
+    INSERT INTO clip_entries SELECT * FROM clips;
+    DROP TABLE clips;
+    ALTER TABLE clip_entries RENAME TO clips;
+    CREATE INDEX clips_identity_clip ON clips (identity, clip, id);
+    ",
+    "
+    ALTER TABLE clips ADD COLUMN expires_at INTEGER;
+    CREATE INDEX clips_expires_at ON clips (expires_at) WHERE expires_at IS NOT NULL;
+    ",
+    "ALTER TABLE clips ADD COLUMN once INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE clips ADD COLUMN filename TEXT;",
+    "ALTER TABLE clips ADD COLUMN e2e INTEGER NOT NULL DEFAULT 0;",
+    // The number of the claim holding a burn-after-read entry, 0 when unclaimed.
+    "
+    ALTER TABLE clips ADD COLUMN claimed INTEGER NOT NULL DEFAULT 0;
+    CREATE INDEX clips_claimed ON clips (claimed) WHERE claimed != 0;
+    ",
+];
+
+/// Keeps every clip in a single SQLite database file.
+///
+/// Claims are numbered from 1 for each server, and those left behind by the previous one are
+/// released on open.
+pub struct SqliteRepository(Mutex<Connection>, AtomicU64);
+
+impl SqliteRepository {
+    pub fn open(path: &Path) -> Result<Self, RepositoryError> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        Self::migrate(Connection::open(path)?)
+    }
+
+    fn migrate(mut conn: Connection) -> Result<Self, RepositoryError> {
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+
+        let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+        for (n, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", n as u32 + 1)?;
+            tx.commit()?;
+        }
+
+        conn.execute("UPDATE clips SET claimed = 0 WHERE claimed != 0", [])?;
+
+        Ok(Self(Mutex::new(conn), AtomicU64::new(1)))
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.0.lock().expect("repository lock poisoned")
+    }
+}
+
+impl Repository for SqliteRepository {
+    fn get(
+        &self,
+        id: &str,
+        clip: Option<&str>,
+        index: usize,
+    ) -> Result<(Paste, Option<Claim>), RepositoryError> {
+        let clip = clip.unwrap_or(DEFAULT_CLIP);
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+
+        let (row_id, _, paste) = tx
+            .query_row(
+                "SELECT id, claimed, payload, content_type, filename, expires_at, once, e2e
+                 FROM clips WHERE identity = ?1 AND clip = ?2 ORDER BY id DESC LIMIT 1 OFFSET ?3",
+                params![id, clip, index as i64],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, i64>(1)? != 0,
+                        Paste {
+                            payload: row.get(2)?,
+                            content_type: row.get(3)?,
+                            filename: row.get(4)?,
+                            expires_at: row
+                                .get::<_, Option<i64>>(5)?
+                                .map(|expires_at| expires_at as u64),
+                            once: row.get(6)?,
+                            e2e: row.get(7)?,
+                        },
+                    ))
+                },
+            )
+            .optional()?
+            .filter(|(_, claimed, _)| !claimed)
+            .ok_or(RepositoryError::NotFound)?;
+
+        if paste.is_expired(unix_time()) {
+            return Err(RepositoryError::Expired);
+        }
+
+        let claim = match paste.once {
+            true => {
+                let entry = self.1.fetch_add(1, Ordering::Relaxed);
+                tx.execute(
+                    "UPDATE clips SET claimed = ?1 WHERE id = ?2",
+                    params![entry as i64, row_id],
+                )?;
+
+                Some(Claim {
+                    id: id.to_string(),
+                    clip: clip.to_string(),
+                    entry,
+                })
+            }
+            false => None,
+        };
+        tx.commit()?;
+
+        Ok((paste, claim))
+    }
+
+    fn commit(&self, claim: &Claim) -> Result<(), RepositoryError> {
+        self.conn().execute(
+            "DELETE FROM clips WHERE claimed = ?1",
+            params![claim.entry as i64],
+        )?;
+
+        Ok(())
+    }
+
+    fn release(&self, claim: &Claim) -> Result<(), RepositoryError> {
+        self.conn().execute(
+            "UPDATE clips SET claimed = 0 WHERE claimed = ?1",
+            params![claim.entry as i64],
+        )?;
+
+        Ok(())
+    }
+
+    fn patch(
+        &self,
+        id: &str,
+        clip: Option<&str>,
+        paste: Paste,
+        depth: usize,
+    ) -> Result<(), RepositoryError> {
+        let clip = clip.unwrap_or(DEFAULT_CLIP);
+        let now = unix_time() as i64;
+
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO clips
+                 (identity, clip, payload, content_type, filename, size, created_at, updated_at,
+                  expires_at, once, e2e)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7, ?8, ?9, ?10)",
+            params![
+                id,
+                clip,
+                paste.payload,
+                paste.content_type,
+                paste.filename,
+                paste.payload.len() as i64,
+                now,
+                paste.expires_at.map(|expires_at| expires_at as i64),
+                paste.once,
+                paste.e2e
+            ],
+        )?;
+        tx.execute(
+            "DELETE FROM clips WHERE identity = ?1 AND clip = ?2 AND id NOT IN (
+                 SELECT id FROM clips WHERE identity = ?1 AND clip = ?2
+                 ORDER BY id DESC LIMIT ?3
+             )",
+            params![id, clip, depth as i64],
+        )?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn history(&self, id: &str, clip: Option<&str>) -> Result<Vec<HistoryEntry>, RepositoryError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT size, created_at, expires_at, once, e2e FROM clips
+             WHERE identity = ?1 AND clip = ?2 ORDER BY id DESC",
+        )?;
+
+        let history = stmt
+            .query_map(params![id, clip.unwrap_or(DEFAULT_CLIP)], |row| {
+                Ok(HistoryEntry {
+                    size: row.get::<_, i64>(0)? as u64,
+                    created_at: row.get::<_, i64>(1)? as u64,
+                    expires_at: row
+                        .get::<_, Option<i64>>(2)?
+                        .map(|expires_at| expires_at as u64),
+                    once: row.get(3)?,
+                    e2e: row.get(4)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok(history)
+    }
+
+    fn list(&self, id: &str) -> Result<Vec<ClipInfo>, RepositoryError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT clip, size, content_type, created_at, entries FROM (
+                 SELECT clip, size, content_type, created_at,
+                     COUNT(*) OVER (PARTITION BY clip) AS entries,
+                     ROW_NUMBER() OVER (PARTITION BY clip ORDER BY id DESC) AS n
+                 FROM clips WHERE identity = ?1
+             )
+             WHERE n = 1 ORDER BY clip",
+        )?;
+
+        let clips = stmt
+            .query_map(params![id], |row| {
+                Ok(ClipInfo {
+                    name: row.get(0)?,
+                    size: row.get::<_, i64>(1)? as u64,
+                    content_type: row.get(2)?,
+                    updated_at: row.get::<_, i64>(3)? as u64,
+                    entries: row.get::<_, i64>(4)? as usize,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok(clips)
+    }
+
+    fn delete(&self, id: &str, clip: Option<&str>) -> Result<(), RepositoryError> {
+        let deleted = self.conn().execute(
+            "DELETE FROM clips WHERE identity = ?1 AND clip = ?2",
+            params![id, clip.unwrap_or(DEFAULT_CLIP)],
+        )?;
+
+        match deleted {
+            0 => Err(RepositoryError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn purge_expired(&self, now: u64) -> Result<usize, RepositoryError> {
+        Ok(self.conn().execute(
+            "DELETE FROM clips WHERE expires_at <= ?1",
+            params![now as i64],
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repository::test::conformance;
+
+    fn paste(payload: &[u8], expires_at: Option<u64>) -> Paste {
+        Paste {
+            payload: payload.to_vec(),
+            expires_at,
+            ..Paste::default()
+        }
+    }
+
+    #[test]
+    fn conforms() {
+        conformance(&SqliteRepository::migrate(Connection::open_in_memory().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn migrations_are_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cliplink.sqlite3");
+
+        SqliteRepository::open(&path)
+            .unwrap()
+            .patch("ssh-ed25519 AAAA", None, paste(b"kept", None), 1)
+            .unwrap();
+
+        let repo = SqliteRepository::open(&path).unwrap();
+        assert_eq!(
+            repo.get("ssh-ed25519 AAAA", None, 0).unwrap().0.payload,
+            b"kept"
+        );
+
+        let version: u32 = repo
+            .conn()
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn releases_claims_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cliplink.sqlite3");
+        let once = Paste {
+            once: true,
+            ..paste(b"secret", None)
+        };
+
+        let repo = SqliteRepository::open(&path).unwrap();
+        repo.patch("ssh-ed25519 AAAA", None, once.clone(), 1)
+            .unwrap();
+        assert!(repo.get("ssh-ed25519 AAAA", None, 0).unwrap().1.is_some());
+        drop(repo);
+
+        let repo = SqliteRepository::open(&path).unwrap();
+        assert_eq!(repo.get("ssh-ed25519 AAAA", None, 0).unwrap().0, once);
+    }
+}
@@ -5,7 +5,10 @@ use serde::{Deserialize, Serialize};
 pub struct Clip {
     pub id: String,
     pub title: String,
+    /// Text for text clips, a data URL for images.
     pub preview: String,
+    pub content_type: String,
+    pub filename: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -42,11 +45,22 @@ pub async fn connect(
                 id: "1".to_string(),
                 title: "Alpha".to_string(),
                 preview: "First clipboard entry".to_string(),
+                content_type: "text/plain; charset=utf-8".to_string(),
+                filename: None,
             },
             Clip {
                 id: "2".to_string(),
                 title: "Beta".to_string(),
                 preview: "Second clipboard entry".to_string(),
+                content_type: "text/markdown; charset=utf-8".to_string(),
+                filename: Some("notes.md".to_string()),
+            },
+            Clip {
+                id: "3".to_string(),
+                title: "Gamma".to_string(),
+                preview: String::new(),
+                content_type: "application/pdf".to_string(),
+                filename: Some("report.pdf".to_string()),
             },
         ],
     })
@@ -177,13 +191,11 @@ fn ConnectPage() -> impl IntoView {
                                         <div class="panel" style="padding:12px; border-radius:14px;">
                                             <div style="display:flex; justify-content:space-between; align-items:center;">
                                                 <div style="font-weight:700; letter-spacing:.08em;">
-                                                    {c.title}
+                                                    {c.title.clone()}
                                                 </div>
-                                                <span class="badge">{c.id}</span>
-                                            </div>
-                                            <div style="margin-top:6px; color:var(--muted); font-family: ui-monospace, SFMono-Regular, Menlo, Monaco, Consolas, 'Liberation Mono', 'Courier New', monospace;">
-                                                {c.preview}
+                                                <span class="badge">{c.content_type.clone()}</span>
                                             </div>
+                                            <ClipPreview clip=c/>
                                         </div>
                                     }).collect_view()}
                                 </div>
@@ -196,6 +208,60 @@ fn ConnectPage() -> impl IntoView {
     }
 }
 
+/// How a clip is previewed, decided by its content type.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PreviewKind {
+    Text,
+    Image,
+    File,
+}
+
+impl PreviewKind {
+    fn of(content_type: &str) -> Self {
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        match essence.split_once('/') {
+            Some(("text", _)) => Self::Text,
+            Some(("application", "json" | "xml" | "javascript")) => Self::Text,
+            // SVG can carry scripts, so it is not rendered inline.
+            Some(("image", "svg+xml")) => Self::File,
+            Some(("image", _)) => Self::Image,
+            _ => Self::File,
+        }
+    }
+}
+
+#[component]
+fn ClipPreview(clip: Clip) -> impl IntoView {
+    match PreviewKind::of(&clip.content_type) {
+        PreviewKind::Text => view! {
+            <div style="margin-top:6px; color:var(--muted); white-space:pre-wrap; font-family: ui-monospace, SFMono-Regular, Menlo, Monaco, Consolas, 'Liberation Mono', 'Courier New', monospace;">
+                {clip.preview}
+            </div>
+        }
+        .into_any(),
+        PreviewKind::Image => view! {
+            <img
+                style="margin-top:8px; max-width:100%; max-height:240px; border-radius:10px;"
+                src=clip.preview
+                alt=clip.filename.unwrap_or(clip.title)
+            />
+        }
+        .into_any(),
+        PreviewKind::File => view! {
+            <div style="margin-top:6px; color:var(--muted);">
+                {clip.filename.unwrap_or_else(|| "Binary clip".to_string())}
+            </div>
+        }
+        .into_any(),
+    }
+}
+
 #[component]
 fn CyberpunkStyles() -> impl IntoView {
     view! {